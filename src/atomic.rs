@@ -0,0 +1,84 @@
+//! The atomics behind every marker. Under `cfg(loom)` these are loom's model
+//! checked types, so races between a mark dropping on one thread and the map
+//! sweeping on another can be explored exhaustively:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --lib loom_
+//! ```
+//!
+//! which needs `loom` declared as a `cfg(loom)` target dependency.
+//!
+//! Markers need pointer sized compare-and-swap, so targets without it, like
+//! thumbv6m, are not supported yet. Only the counter for mark ids is 64-bit,
+//! it takes a lock instead where 64-bit atomics are missing.
+
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::AtomicUsize;
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::Ordering;
+#[cfg(not(loom))]
+pub(crate) use std::sync::Arc;
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::AtomicUsize;
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::Ordering;
+#[cfg(loom)]
+pub(crate) use loom::sync::Arc;
+
+
+/// Next process-wide unique mark id. Ids only need to be unique, so this
+/// stays outside of loom's model.
+#[cfg(target_has_atomic = "64")]
+pub(crate) fn next_id() -> u64 {
+    static NEXT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+}
+
+#[cfg(not(target_has_atomic = "64"))]
+pub(crate) fn next_id() -> u64 {
+    static NEXT_ID: std::sync::Mutex<u64> = std::sync::Mutex::new(0);
+    let mut next = NEXT_ID.lock().unwrap_or_else(|e| e.into_inner());
+    *next += 1;
+    *next - 1
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_drop_vs_gc() {
+    loom::model(|| {
+        let mut wm : super::HashMap<u32, u32> = super::HashMap::new();
+        let (mark, _) = wm.insert(1, 1);
+        let (_keep, _) = wm.insert(2, 2);
+
+        let t = loom::thread::spawn(move || drop(mark));
+        wm.gc();
+        t.join().unwrap();
+
+        // whichever way the sweep raced the drop, the entry is gone now
+        // and the live neighbour survived.
+        assert_eq!(wm.get(&1), None);
+        assert_eq!(wm.get(&2), Some(&2));
+        wm.gc();
+        assert_eq!(wm.len(), 1);
+        assert_eq!(wm.gc.load(Ordering::SeqCst), 0);
+    });
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_drop_vs_insert() {
+    loom::model(|| {
+        let mut wm : super::HashMap<u32, u32> = super::HashMap::new();
+        let (mark, _) = wm.insert(1, 1);
+
+        let t = loom::thread::spawn(move || drop(mark));
+        let (_mark, old) = wm.insert(1, 2);
+        t.join().unwrap();
+
+        // the old value is only handed back if it was still live.
+        assert!(old == None || old == Some(1));
+        assert_eq!(wm.get(&1), Some(&2));
+        assert_eq!(wm.gc.load(Ordering::SeqCst), 0);
+    });
+}
@@ -0,0 +1,164 @@
+use super::atomic::AtomicUsize;
+use super::atomic::Ordering;
+use super::atomic::Arc;
+use super::mark::Marker;
+use super::GcReport;
+use super::MarkOnDrop;
+
+/// Bidirectional variant. Both directions of a pair share one marker, so
+/// dropping its mark removes the pair from both sides.
+pub struct BiMap<L, R> {
+    left:  std::collections::HashMap<L, (R, Arc<Marker>)>,
+    right: std::collections::HashMap<R, (L, Arc<Marker>)>,
+    gc:    Arc<AtomicUsize>,
+}
+
+impl<L,R> Default for BiMap<L,R>
+    where L: std::cmp::Eq + std::hash::Hash + Clone,
+          R: std::cmp::Eq + std::hash::Hash + Clone,
+{
+    fn default() -> Self {
+        BiMap {
+            left:  std::collections::HashMap::new(),
+            right: std::collections::HashMap::new(),
+            gc:    Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl<L, R> BiMap<L, R>
+    where L: std::cmp::Eq + std::hash::Hash + Clone,
+          R: std::cmp::Eq + std::hash::Hash + Clone,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+// removes `k` from `map` only if it still belongs to the pair behind `marker`,
+// a newer pair may have taken the slot in the meantime.
+fn unlink<K, V>(map: &mut std::collections::HashMap<K, (V, Arc<Marker>)>, k: &K, marker: &Arc<Marker>)
+    where K: std::cmp::Eq + std::hash::Hash,
+{
+    if map.get(k).map(|(_, m)| Arc::ptr_eq(m, marker)).unwrap_or(false) {
+        map.remove(k);
+    }
+}
+
+impl<L,R> BiMap<L,R>
+    where L: std::cmp::Eq + std::hash::Hash + Clone,
+          R: std::cmp::Eq + std::hash::Hash + Clone,
+{
+    /// Insert the pair `l <-> r`. Any live pair that shared either side is
+    /// removed and returned.
+    pub fn insert(&mut self, l: L, r: R) -> (MarkOnDrop, Vec<(L, R)>) {
+        self.maybe_gc();
+        let mut displaced = Vec::new();
+
+        if let Some((r0, marker)) = self.left.remove(&l) {
+            unlink(&mut self.right, &r0, &marker);
+            if marker.detach(&self.gc) {
+                displaced.push((l.clone(), r0));
+            }
+        }
+        if let Some((l0, marker)) = self.right.remove(&r) {
+            unlink(&mut self.left, &l0, &marker);
+            if marker.detach(&self.gc) {
+                displaced.push((l0, r.clone()));
+            }
+        }
+
+        let mark = MarkOnDrop::new(&self.gc);
+        self.left.insert(l.clone(), (r.clone(), mark.marker.clone()));
+        self.right.insert(r, (l, mark.marker.clone()));
+        (mark, displaced)
+    }
+
+    pub fn get_by_left(&mut self, l: &L) -> Option<&R> {
+        let dead = match self.left.get(l) {
+            Some((r, marker)) if marker.collect(&self.gc) => Some((r.clone(), marker.clone())),
+            _ => None,
+        };
+        if let Some((r, marker)) = dead {
+            self.left.remove(l);
+            unlink(&mut self.right, &r, &marker);
+        }
+        self.left.get(l).map(|(r, _)| r)
+    }
+
+    pub fn get_by_right(&mut self, r: &R) -> Option<&L> {
+        let dead = match self.right.get(r) {
+            Some((l, marker)) if marker.collect(&self.gc) => Some((l.clone(), marker.clone())),
+            _ => None,
+        };
+        if let Some((l, marker)) = dead {
+            self.right.remove(r);
+            unlink(&mut self.left, &l, &marker);
+        }
+        self.right.get(r).map(|(l, _)| l)
+    }
+
+    pub fn len(&self) -> usize {
+        self.left.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    fn maybe_gc(&mut self) {
+        if self.gc.load(Ordering::SeqCst) > self.len() / 2 {
+            self.gc();
+        }
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        let start = std::time::Instant::now();
+        let scanned = self.len();
+        // sweep by the dead pairs of one side, so a mark dropping halfway
+        // through cannot leave a pair behind on only one side.
+        let dead : Vec<(L, R, Arc<Marker>)> = self.left.iter()
+            .filter(|(_, (_, marker))| marker.collect(&self.gc))
+            .map(|(l, (r, marker))| (l.clone(), r.clone(), marker.clone()))
+            .collect();
+        for (l, r, marker) in dead {
+            self.left.remove(&l);
+            unlink(&mut self.right, &r, &marker);
+        }
+        GcReport {
+            scanned,
+            collected: scanned - self.len(),
+            elapsed:   start.elapsed(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&L, &R)> {
+        self.left.iter().filter_map(|(l, (r, marker))|{
+            if marker.is_dead() {
+                None
+            } else {
+                Some((l, r))
+            }
+        })
+    }
+}
+
+
+#[test]
+fn bimap_pairs() {
+    let mut bm : BiMap<u32, &'static str> = BiMap::new();
+    let (a, _) = bm.insert(1, "alice");
+    let (_b, _) = bm.insert(2, "bob");
+    assert_eq!(bm.get_by_right(&"alice"), Some(&1));
+
+    drop(a);
+    assert_eq!(bm.get_by_left(&1), None);
+    assert_eq!(bm.get_by_right(&"alice"), None);
+
+    let (_c, displaced) = bm.insert(3, "bob");
+    assert_eq!(displaced, vec![(2, "bob")]);
+    assert_eq!(bm.get_by_left(&2), None);
+    assert_eq!(bm.get_by_right(&"bob"), Some(&3));
+    assert_eq!(bm.gc().scanned, 1);
+    assert_eq!(bm.len(), 1);
+}
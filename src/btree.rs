@@ -0,0 +1,133 @@
+use std::ops::RangeBounds;
+
+use super::storage::GcMap;
+use super::storage::Stored;
+use super::GcReport;
+use super::MarkOnDrop;
+
+/// Ordered variant of the map, backed by a `std::collections::BTreeMap`.
+pub struct BTreeMap<K, V> {
+    v: GcMap<K, V, std::collections::BTreeMap<K, Stored<V>>>,
+}
+
+impl<K,V> Default for BTreeMap<K,V>
+    where K: std::cmp::Ord
+{
+    fn default() -> Self {
+        BTreeMap {
+            v: GcMap::new(),
+        }
+    }
+}
+
+impl<K, V> BTreeMap<K, V>
+    where K: std::cmp::Ord
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K,V> BTreeMap<K,V>
+    where K: std::cmp::Ord
+{
+    pub fn insert(&mut self, k: K, v: V) -> (MarkOnDrop, Option<V>) {
+        self.v.insert(k, v)
+    }
+
+    pub fn get<Q>(&mut self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Ord,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.v.get(k)
+    }
+
+    pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+        where Q: ?Sized + std::cmp::Ord,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.v.get_mut(k)
+    }
+
+    pub fn len(&self) -> usize {
+        self.v.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        self.v.gc()
+    }
+
+    /// Live entries in key order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&K, &V)> {
+        self.v.storage.iter().filter_map(|(k, e)|{
+            if e.marker.is_dead() {
+                None
+            } else {
+                Some((k, &e.value))
+            }
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl DoubleEndedIterator<Item = (&K, &mut V)> {
+        self.v.storage.iter_mut().filter_map(|(k, e)|{
+            if e.marker.is_dead() {
+                None
+            } else {
+                Some((k, &mut e.value))
+            }
+        })
+    }
+
+    /// Live entries within `range`, in key order.
+    pub fn range<Q, R>(&self, range: R) -> impl DoubleEndedIterator<Item = (&K, &V)>
+        where Q: ?Sized + std::cmp::Ord,
+              K: std::borrow::Borrow<Q>,
+              R: RangeBounds<Q>,
+    {
+        self.v.storage.range(range).filter_map(|(k, e)|{
+            if e.marker.is_dead() {
+                None
+            } else {
+                Some((k, &e.value))
+            }
+        })
+    }
+
+    pub fn range_mut<Q, R>(&mut self, range: R) -> impl DoubleEndedIterator<Item = (&K, &mut V)>
+        where Q: ?Sized + std::cmp::Ord,
+              K: std::borrow::Borrow<Q>,
+              R: RangeBounds<Q>,
+    {
+        self.v.storage.range_mut(range).filter_map(|(k, e)|{
+            if e.marker.is_dead() {
+                None
+            } else {
+                Some((k, &mut e.value))
+            }
+        })
+    }
+}
+
+
+#[test]
+fn btree_range() {
+    let mut wm : BTreeMap<(u64, u32), &'static str> = BTreeMap::new();
+    let (_a, _) = wm.insert((10, 1), "a");
+    let (b, _)  = wm.insert((20, 2), "b");
+    let (_c, _) = wm.insert((30, 3), "c");
+    let (_d, _) = wm.insert((40, 4), "d");
+
+    drop(b);
+    let expired : Vec<_> = wm.range(..(35, 0)).map(|(_, v)| *v).collect();
+    assert_eq!(expired, vec!["a", "c"]);
+    assert_eq!(wm.iter().next_back(), Some((&(40, 4), &"d")));
+    assert_eq!(wm.get(&(20, 2)), None);
+
+    drop((_a, _c, _d));
+    assert_eq!(wm.len(), 3);
+    assert!(wm.is_empty());
+}
@@ -0,0 +1,137 @@
+use std::sync::Arc;
+
+use super::GcReport;
+use super::HashMap;
+use super::MarkOnDrop;
+
+/// Handle to a `HashMap` that is only copied when it is modified while
+/// another fork still shares it. Forks share marks like a cloned map does.
+pub struct CowHashMap<K, V> {
+    inner: Arc<HashMap<K, V>>,
+}
+
+impl<K, V> Clone for CowHashMap<K, V> {
+    fn clone(&self) -> Self {
+        CowHashMap{inner: self.inner.clone()}
+    }
+}
+
+impl<K,V> Default for CowHashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    fn default() -> Self {
+        CowHashMap{inner: Arc::new(HashMap::new())}
+    }
+}
+
+impl<K, V> From<HashMap<K, V>> for CowHashMap<K, V> {
+    fn from(map: HashMap<K, V>) -> Self {
+        CowHashMap{inner: Arc::new(map)}
+    }
+}
+
+impl<K, V> CowHashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K,V> CowHashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash + Clone,
+          V: Clone,
+{
+    /// A cheap copy, storage is shared until either side is modified.
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
+
+    /// True while another fork shares the storage.
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.inner) > 1
+    }
+
+    // copies the table first if it is shared, which also drops dead entries
+    fn inner_mut(&mut self) -> &mut HashMap<K, V> {
+        Arc::make_mut(&mut self.inner)
+    }
+
+    pub fn insert(&mut self, k: K, v: V) -> (MarkOnDrop, Option<V>) {
+        self.inner_mut().insert(k, v)
+    }
+
+    /// Lookup without copying, dead entries are skipped.
+    pub fn get<Q>(&self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.inner.get_live(k)
+    }
+
+    pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.inner_mut().get_mut(k)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        self.inner_mut().gc()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.inner.iter()
+    }
+
+    /// The map itself, copied if another fork still shares it.
+    pub fn into_inner(self) -> HashMap<K, V> {
+        Arc::try_unwrap(self.inner).unwrap_or_else(|inner| (*inner).clone())
+    }
+}
+
+
+#[test]
+fn cow_fork() {
+    let mut wm : CowHashMap<u32, u32> = CowHashMap::new();
+    let (_a, _) = wm.insert(1, 10);
+    let (b, _)  = wm.insert(2, 20);
+
+    let mut fork = wm.fork();
+    assert!(wm.is_shared());
+    assert_eq!(fork.get(&1), Some(&10));
+
+    *fork.get_mut(&1).unwrap() = 11;
+    assert!(!wm.is_shared());
+    assert_eq!(wm.get(&1), Some(&10));
+    assert_eq!(fork.get(&1), Some(&11));
+
+    // marks are shared, so releasing one ends the entry in both forks
+    drop(b);
+    assert_eq!(wm.get(&2), None);
+    assert_eq!(fork.get(&2), None);
+    assert_eq!(fork.into_inner().len(), 2);
+}
+
+#[test]
+fn cow_fork_overwrite() {
+    let mut wm : CowHashMap<u32, u32> = CowHashMap::new();
+    let (_a, _) = wm.insert(1, 10);
+
+    let mut fork = wm.fork();
+    let (_b, old) = fork.insert(1, 11);
+    assert_eq!(old, Some(10));
+    assert_eq!(wm.get(&1), Some(&10));
+    assert_eq!(fork.get(&1), Some(&11));
+
+    drop(fork);
+    assert_eq!(wm.get(&1), Some(&10));
+}
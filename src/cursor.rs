@@ -0,0 +1,104 @@
+use super::HashMap;
+
+/// Walks a map and can change or remove the entry it is on, see `HashMap::cursor`.
+///
+/// The keys stored when the cursor is created are visited in the order of
+/// the table, dead entries are collected on the way. Only the keys are
+/// copied, the entries stay in the map.
+pub struct Cursor<'a, K: 'a, V: 'a>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    map:     &'a mut HashMap<K, V>,
+    rest:    std::vec::IntoIter<K>,
+    current: Option<K>,
+}
+
+impl<'a, K, V> Cursor<'a, K, V>
+    where K: std::cmp::Eq + std::hash::Hash + Clone
+{
+    pub(crate) fn new(map: &'a mut HashMap<K, V>) -> Self {
+        let rest = map.v.keys().cloned().collect::<Vec<_>>().into_iter();
+        Cursor {
+            map,
+            rest,
+            current: None,
+        }
+    }
+}
+
+impl<'a, K, V> Cursor<'a, K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    /// Move to the next live entry. Returns false once all entries were visited.
+    pub fn move_next(&mut self) -> bool {
+        self.current = None;
+        for k in self.rest.by_ref() {
+            let dead = match self.map.v.get(&k) {
+                Some(slot) => slot.collect(&self.map.gc),
+                None => continue,
+            };
+            if !dead {
+                self.current = Some(k);
+                return true;
+            }
+            if let Some(slot) = self.map.v.remove(&k) {
+                self.map.hooks.collected(k, slot);
+            }
+        }
+        false
+    }
+
+    pub fn key(&self) -> Option<&K> {
+        self.current.as_ref()
+    }
+
+    pub fn value(&self) -> Option<&V> {
+        let k = self.current.as_ref()?;
+        self.map.v.get(k).map(|slot| &slot.value)
+    }
+
+    pub fn value_mut(&mut self) -> Option<&mut V> {
+        let k = self.current.as_ref()?;
+        self.map.v.get_mut(k).map(|slot| &mut slot.value)
+    }
+
+    /// Remove the current entry, its outstanding mark no longer refers to
+    /// anything. The cursor is on no entry until the next `move_next`.
+    pub fn remove(&mut self) -> Option<(K, V)> {
+        let k = self.current.take()?;
+        let (k, slot) = self.map.v.remove_entry(&k)?;
+        slot.detach(&self.map.gc);
+        self.map.hooks.removed(&k);
+        Some((k, slot.value))
+    }
+}
+
+
+#[test]
+fn cursor_maintenance() {
+    let mut wm : HashMap<u32, u32> = HashMap::with_manual_gc();
+    let mut marks : Vec<super::MarkOnDrop> = (0..6).map(|i| wm.insert(i, i).0).collect();
+    marks.truncate(4);
+
+    {
+        let mut cursor = wm.cursor();
+        while cursor.move_next() {
+            if cursor.key() == Some(&0) {
+                cursor.remove();
+            } else {
+                *cursor.value_mut().unwrap() *= 10;
+            }
+        }
+    }
+    let mut live : Vec<_> = wm.iter().map(|(k, v)| (*k, *v)).collect();
+    live.sort();
+    assert_eq!(live, vec![(1, 10), (2, 20), (3, 30)]);
+    assert_eq!(wm.len(), 3);
+
+    // stopping early keeps the rest
+    {
+        let mut cursor = wm.cursor();
+        assert!(cursor.move_next());
+    }
+    assert_eq!(wm.len(), 3);
+}
@@ -0,0 +1,141 @@
+use super::atomic::AtomicUsize;
+use super::atomic::Ordering;
+use super::atomic::Arc;
+use super::mark::Marker;
+use super::GcReport;
+use super::MarkOnDrop;
+
+/// Queue variant. Items whose mark dropped are skipped lazily by `pop_*`,
+/// `front`/`back` and iteration, and compacted out by `gc`.
+pub struct VecDeque<V> {
+    v:  std::collections::VecDeque<(V, Arc<Marker>)>,
+    gc: Arc<AtomicUsize>,
+}
+
+impl<V> Default for VecDeque<V> {
+    fn default() -> Self {
+        VecDeque {
+            v:  std::collections::VecDeque::new(),
+            gc: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl<V> VecDeque<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<V> VecDeque<V> {
+    pub fn push_back(&mut self, v: V) -> MarkOnDrop {
+        self.maybe_gc();
+        let mark = MarkOnDrop::new(&self.gc);
+        self.v.push_back((v, mark.marker.clone()));
+        mark
+    }
+
+    pub fn push_front(&mut self, v: V) -> MarkOnDrop {
+        self.maybe_gc();
+        let mark = MarkOnDrop::new(&self.gc);
+        self.v.push_front((v, mark.marker.clone()));
+        mark
+    }
+
+    pub fn pop_front(&mut self) -> Option<V> {
+        while let Some((v, marker)) = self.v.pop_front() {
+            if marker.detach(&self.gc) {
+                return Some(v);
+            }
+        }
+        None
+    }
+
+    pub fn pop_back(&mut self) -> Option<V> {
+        while let Some((v, marker)) = self.v.pop_back() {
+            if marker.detach(&self.gc) {
+                return Some(v);
+            }
+        }
+        None
+    }
+
+    pub fn front(&mut self) -> Option<&V> {
+        while self.v.front().map(|(_, marker)| marker.collect(&self.gc)).unwrap_or(false) {
+            self.v.pop_front();
+        }
+        self.v.front().map(|(v, _)| v)
+    }
+
+    pub fn back(&mut self) -> Option<&V> {
+        while self.v.back().map(|(_, marker)| marker.collect(&self.gc)).unwrap_or(false) {
+            self.v.pop_back();
+        }
+        self.v.back().map(|(v, _)| v)
+    }
+
+    pub fn len(&self) -> usize {
+        self.v.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    fn maybe_gc(&mut self) {
+        if self.gc.load(Ordering::SeqCst) > self.len() / 2 {
+            self.gc();
+        }
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        let start = std::time::Instant::now();
+        let scanned = self.len();
+        let gc = &self.gc;
+        self.v.retain(|(_, marker)| !marker.collect(gc));
+        GcReport {
+            scanned,
+            collected: scanned - self.len(),
+            elapsed:   start.elapsed(),
+        }
+    }
+
+    /// Live items from front to back.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &V> {
+        self.v.iter().filter_map(|(v, marker)|{
+            if marker.is_dead() {
+                None
+            } else {
+                Some(v)
+            }
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut V> {
+        self.v.iter_mut().filter_map(|(v, marker)|{
+            if marker.is_dead() {
+                None
+            } else {
+                Some(v)
+            }
+        })
+    }
+}
+
+
+#[test]
+fn deque_cancel() {
+    let mut q : VecDeque<u32> = VecDeque::new();
+    let a = q.push_back(1);
+    let _b = q.push_back(2);
+    let c = q.push_back(3);
+    let _d = q.push_front(0);
+
+    drop(a);
+    drop(c);
+    assert_eq!(q.iter().collect::<Vec<_>>(), vec![&0, &2]);
+    assert_eq!(q.back(), Some(&2));
+    assert_eq!(q.pop_front(), Some(0));
+    assert_eq!(q.pop_front(), Some(2));
+    assert_eq!(q.pop_front(), None);
+}
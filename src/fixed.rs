@@ -0,0 +1,381 @@
+//! Variant with inline storage for at most `N` entries, nothing is allocated
+//! per insert.
+//!
+//! Instead of a shared marker per entry, the liveness of every slot is a word
+//! in a `Markers` table that the map and its marks both borrow:
+//!
+//! ```
+//! let markers = gcmap::fixed::Markers::<16>::new();
+//! let mut wm  = gcmap::fixed::HashMap::<u32, &str, 16>::new(&markers);
+//! let (mark, _) = wm.insert(1, "one").unwrap();
+//! assert_eq!(wm.get(&1), Some(&"one"));
+//! drop(mark);
+//! assert_eq!(wm.get(&1), None);
+//! ```
+
+use std::hash::Hasher;
+
+use super::atomic::AtomicUsize;
+use super::atomic::Ordering;
+use super::mark::uncount;
+use super::GcReport;
+
+// the low bits of a slot word are its state, the rest is the generation of
+// the entry in it, so marks of earlier entries in the same slot do nothing.
+const LIVE:      usize = 0;
+const DEAD:      usize = 1;
+const COLLECTED: usize = 2;
+const STATE:     usize = 3;
+
+/// Liveness of the `N` slots of one `HashMap` at a time.
+pub struct Markers<const N: usize> {
+    slots:   [AtomicUsize; N],
+    gc:      AtomicUsize,
+    claimed: std::sync::atomic::AtomicBool,
+}
+
+impl<const N: usize> Default for Markers<N> {
+    fn default() -> Self {
+        Markers {
+            slots:   std::array::from_fn(|_| AtomicUsize::new(COLLECTED)),
+            gc:      AtomicUsize::new(0),
+            claimed: std::sync::atomic::AtomicBool::new(false),
+        }
+    }
+}
+
+impl<const N: usize> Markers<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn is_dead(&self, i: usize) -> bool {
+        self.slots[i].load(Ordering::SeqCst) & STATE != LIVE
+    }
+
+    /// Start a new generation in slot `i` and return the word of its mark.
+    fn arm(&self, i: usize) -> usize {
+        let word = (self.slots[i].load(Ordering::SeqCst) & !STATE).wrapping_add(STATE + 1) | LIVE;
+        self.slots[i].store(word, Ordering::SeqCst);
+        word
+    }
+
+    // marks only ever turn a slot from live to dead, so a dead slot is
+    // stable and can be stored over.
+    fn collect(&self, i: usize) -> bool {
+        let word = self.slots[i].load(Ordering::SeqCst);
+        match word & STATE {
+            LIVE => false,
+            DEAD => {
+                self.slots[i].store((word & !STATE) | COLLECTED, Ordering::SeqCst);
+                uncount(&self.gc);
+                true
+            },
+            _ => true,
+        }
+    }
+
+    fn detach(&self, i: usize) -> bool {
+        let word = self.slots[i].load(Ordering::SeqCst) & !STATE;
+        match self.slots[i].swap(word | COLLECTED, Ordering::SeqCst) & STATE {
+            LIVE => true,
+            DEAD => {
+                uncount(&self.gc);
+                false
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Keeps an entry of a `fixed::HashMap` alive, like `MarkOnDrop`.
+pub struct Mark<'a> {
+    slot: &'a AtomicUsize,
+    gc:   &'a AtomicUsize,
+    word: usize,
+}
+
+impl<'a> Drop for Mark<'a> {
+    fn drop(&mut self) {
+        self.gc.fetch_add(1, Ordering::SeqCst);
+        if self.slot.compare_exchange(self.word, self.word | DEAD, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            uncount(self.gc);
+        }
+    }
+}
+
+/// Returned by `insert` when all `N` slots hold live entries.
+pub struct Full<K, V> {
+    pub key:   K,
+    pub value: V,
+}
+
+impl<K, V> std::fmt::Debug for Full<K, V>
+    where K: std::fmt::Debug, V: std::fmt::Debug
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Full")
+            .field("key", &self.key)
+            .field("value", &self.value)
+            .finish()
+    }
+}
+
+impl<K, V> std::fmt::Display for Full<K, V>
+    where K: std::fmt::Debug, V: std::fmt::Debug
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "failed to insert {:?} for key {:?}, the map is full", self.value, self.key)
+    }
+}
+
+impl<K, V> std::error::Error for Full<K, V>
+    where K: std::fmt::Debug, V: std::fmt::Debug
+{}
+
+/// Open addressing table over `N` inline slots.
+///
+/// `entries` never move, so slot `i` of the markers always belongs to
+/// `entries[i]`. Only the probe table `index` is reordered on removal.
+pub struct HashMap<'a, K, V, const N: usize> {
+    markers:  &'a Markers<N>,
+    entries:  [Option<(K, V)>; N],
+    index:    [Option<usize>; N],
+    free:     [usize; N],
+    free_len: usize,
+}
+
+impl<'a, K, V, const N: usize> HashMap<'a, K, V, N>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    /// Panics if `markers` is already used by another map. A map without
+    /// slots does not compile:
+    ///
+    /// ```compile_fail
+    /// let markers = gcmap::fixed::Markers::<0>::new();
+    /// let wm = gcmap::fixed::HashMap::<u32, u32, 0>::new(&markers);
+    /// ```
+    pub fn new(markers: &'a Markers<N>) -> Self {
+        // every probe is modulo N
+        const { assert!(N > 0, "a fixed map needs at least one slot") };
+        assert!(!markers.claimed.swap(true, std::sync::atomic::Ordering::SeqCst), "markers are in use by another map");
+        HashMap {
+            markers,
+            entries:  std::array::from_fn(|_| None),
+            index:    [None; N],
+            free:     std::array::from_fn(|i| N - 1 - i),
+            free_len: N,
+        }
+    }
+}
+
+impl<'a, K, V, const N: usize> HashMap<'a, K, V, N>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Number of stored entries, including dead ones.
+    pub fn len(&self) -> usize {
+        N - self.free_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    // fixed keys, so the table needs no hasher state
+    fn home<Q>(k: &Q) -> usize
+        where Q: ?Sized + std::hash::Hash,
+    {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        k.hash(&mut hasher);
+        (hasher.finish() % N as u64) as usize
+    }
+
+    fn find<Q>(&self, k: &Q) -> Option<usize>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        let home = Self::home(k);
+        for probe in 0..N {
+            let pos = (home + probe) % N;
+            match self.index[pos] {
+                None => return None,
+                Some(i) => match self.entries[i] {
+                    Some((ref key, _)) if key.borrow() == k => return Some(pos),
+                    _ => {},
+                },
+            }
+        }
+        None
+    }
+
+    /// Take the entry at probe position `pos` out of the table.
+    fn remove_at(&mut self, pos: usize) -> (K, V) {
+        let i = self.index[pos].take().unwrap();
+        self.free[self.free_len] = i;
+        self.free_len += 1;
+
+        // shift the rest of the probe run back, so lookups still find it
+        let mut hole = pos;
+        let mut next = (pos + 1) % N;
+        while let Some(j) = self.index[next] {
+            let home = Self::home(&self.entries[j].as_ref().unwrap().0);
+            if (next + N - home) % N >= (next + N - hole) % N {
+                self.index[hole] = self.index[next].take();
+                hole = next;
+            }
+            next = (next + 1) % N;
+        }
+        self.entries[i].take().unwrap()
+    }
+
+    fn remove_dead<Q>(&mut self, k: &Q) -> Option<usize>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        let pos = self.find(k)?;
+        if self.markers.collect(self.index[pos].unwrap()) {
+            self.remove_at(pos);
+            return None;
+        }
+        Some(pos)
+    }
+
+    /// Insert, collecting dead entries first if all slots are taken.
+    /// Fails if there still is no free slot afterwards.
+    pub fn insert(&mut self, k: K, v: V) -> Result<(Mark<'a>, Option<V>), Full<K, V>> {
+        if let Some(pos) = self.remove_dead(&k) {
+            let i = self.index[pos].unwrap();
+            let old = self.entries[i].as_mut().map(|(_, old)| std::mem::replace(old, v));
+            let old = if self.markers.detach(i) { old } else { None };
+            return Ok((self.arm(i), old));
+        }
+
+        if self.free_len == 0 {
+            self.gc();
+        }
+        if self.free_len == 0 {
+            return Err(Full{key: k, value: v});
+        }
+
+        self.free_len -= 1;
+        let i = self.free[self.free_len];
+        let home = Self::home(&k);
+        let pos = (0..N).map(|probe| (home + probe) % N).find(|&pos| self.index[pos].is_none()).unwrap();
+        self.index[pos] = Some(i);
+        self.entries[i] = Some((k, v));
+        Ok((self.arm(i), None))
+    }
+
+    fn arm(&self, i: usize) -> Mark<'a> {
+        let markers = self.markers;
+        Mark {
+            word: markers.arm(i),
+            slot: &markers.slots[i],
+            gc:   &markers.gc,
+        }
+    }
+
+    pub fn get<Q>(&mut self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        let i = self.index[self.remove_dead(k)?]?;
+        self.entries[i].as_ref().map(|(_, v)| v)
+    }
+
+    pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        let i = self.index[self.remove_dead(k)?]?;
+        self.entries[i].as_mut().map(|(_, v)| v)
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        let start = std::time::Instant::now();
+        let scanned = self.len();
+        for i in 0..N {
+            if self.entries[i].is_none() || !self.markers.collect(i) {
+                continue;
+            }
+            let home = Self::home(&self.entries[i].as_ref().unwrap().0);
+            let pos = (0..N).map(|probe| (home + probe) % N).find(|&pos| self.index[pos] == Some(i)).unwrap();
+            self.remove_at(pos);
+        }
+        GcReport {
+            scanned,
+            collected: scanned - self.len(),
+            elapsed:   start.elapsed(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        let markers = self.markers;
+        self.entries.iter().enumerate().filter_map(move |(i, entry)| match *entry {
+            Some((ref k, ref v)) if !markers.is_dead(i) => Some((k, v)),
+            _ => None,
+        })
+    }
+}
+
+impl<'a, K, V, const N: usize> Drop for HashMap<'a, K, V, N> {
+    fn drop(&mut self) {
+        // marks may outlive the map, they must not count towards the next one
+        for i in 0..N {
+            if self.entries[i].is_some() {
+                self.markers.detach(i);
+            }
+        }
+        self.markers.claimed.store(false, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+
+#[test]
+fn fixed_full() {
+    let markers = Markers::<4>::new();
+    let mut wm : HashMap<u32, u32, 4> = HashMap::new(&markers);
+    let mut marks : Vec<Mark> = (0..4).map(|i| wm.insert(i, i).unwrap().0).collect();
+
+    let err = wm.insert(4, 4).err().unwrap();
+    assert_eq!((err.key, err.value), (4, 4));
+
+    // a dead entry makes room, a replaced one hands back the old value
+    marks.remove(1);
+    let (_e, _) = wm.insert(4, 4).unwrap();
+    let (_f, old) = wm.insert(2, 20).unwrap();
+    assert_eq!(old, Some(2));
+    assert_eq!(wm.get(&1), None);
+    assert_eq!(wm.get(&2), Some(&20));
+
+    // the replaced mark no longer refers to the entry
+    marks.remove(1);
+    assert_eq!(wm.get(&2), Some(&20));
+    assert_eq!(markers.gc.load(Ordering::SeqCst), 0);
+
+    let mut live : Vec<_> = wm.iter().map(|(k, v)| (*k, *v)).collect();
+    live.sort();
+    assert_eq!(live, vec![(0, 0), (2, 20), (3, 3), (4, 4)]);
+}
+
+#[test]
+fn fixed_probe_runs() {
+    let markers = Markers::<8>::new();
+    let mut wm : HashMap<u32, u32, 8> = HashMap::new(&markers);
+    for round in 0..20 {
+        let mut marks : Vec<Mark> = (0..8).map(|i| wm.insert(round * 8 + i, i).unwrap().0).collect();
+        let mut i = 0;
+        marks.retain(|_| { i += 1; i % 2 == 0 });
+        assert_eq!(wm.gc().collected, 4);
+        for i in 0..8 {
+            assert_eq!(wm.get(&(round * 8 + i)).is_some(), i % 2 == 1);
+        }
+        drop(marks);
+        wm.gc();
+        assert_eq!(wm.len(), 0);
+    }
+}
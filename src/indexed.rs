@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+
+use super::GcReport;
+use super::HashMap;
+use super::MarkOnDrop;
+
+type Indexer<V, I> = Box<dyn Fn(&V) -> I + Send>;
+
+/// Map with a secondary index over its values, maintained on insert, update
+/// and collection.
+///
+/// Entries can also disappear lazily inside the inner map, so the index may
+/// name keys that are gone or were reinserted under another index value.
+/// Lookups check every candidate against the map, and `gc` prunes them.
+pub struct IndexedHashMap<K, V, I> {
+    map:     HashMap<K, V>,
+    index:   std::collections::HashMap<I, HashSet<K>>,
+    indexer: Indexer<V, I>,
+}
+
+impl<K, V, I> IndexedHashMap<K, V, I>
+    where K: std::cmp::Eq + std::hash::Hash + Clone,
+          I: std::cmp::Eq + std::hash::Hash,
+{
+    pub fn new<F>(indexer: F) -> Self
+        where F: Fn(&V) -> I + Send + 'static,
+    {
+        IndexedHashMap {
+            // sweeps go through our own gc, so the index hears about them
+            map:     HashMap::with_manual_gc(),
+            index:   std::collections::HashMap::new(),
+            indexer: Box::new(indexer),
+        }
+    }
+}
+
+impl<K,V,I> IndexedHashMap<K,V,I>
+    where K: std::cmp::Eq + std::hash::Hash + Clone,
+          I: std::cmp::Eq + std::hash::Hash,
+{
+    pub fn insert(&mut self, k: K, v: V) -> (MarkOnDrop, Option<V>) {
+        if self.map.needs_gc() {
+            self.gc();
+        }
+        let key = k.clone();
+        self.index.entry((self.indexer)(&v)).or_default().insert(k.clone());
+        let (mark, old) = self.map.insert(k, v);
+        if let Some(ref old) = old {
+            self.unindex(&key, old);
+        }
+        (mark, old)
+    }
+
+    fn unindex(&mut self, k: &K, v: &V) {
+        let i = (self.indexer)(v);
+        // the key may have been indexed again under the same value
+        if self.map.get_live(k).map(|v| (self.indexer)(v) == i).unwrap_or(false) {
+            return;
+        }
+        if let Some(keys) = self.index.get_mut(&i) {
+            keys.remove(k);
+            if keys.is_empty() {
+                self.index.remove(&i);
+            }
+        }
+    }
+
+    pub fn get<Q>(&self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.map.get_live(k)
+    }
+
+    /// Change the value for `k` and index it again. Returns false if there
+    /// is no live entry.
+    pub fn update<F>(&mut self, k: &K, f: F) -> bool
+        where F: FnOnce(&mut V),
+    {
+        let value = match self.map.get_mut(k) {
+            Some(value) => value,
+            None => return false,
+        };
+        let before = (self.indexer)(value);
+        f(value);
+        let after = (self.indexer)(value);
+        if before != after {
+            if let Some(keys) = self.index.get_mut(&before) {
+                keys.remove(k);
+                if keys.is_empty() {
+                    self.index.remove(&before);
+                }
+            }
+            self.index.entry(after).or_default().insert(k.clone());
+        }
+        true
+    }
+
+    /// Some live entry indexed under `i`.
+    pub fn get_by_index(&self, i: &I) -> Option<(&K, &V)> {
+        self.iter_by_index(i).next()
+    }
+
+    /// All live entries indexed under `i`, in no particular order.
+    pub fn iter_by_index<'a>(&'a self, i: &I) -> impl Iterator<Item = (&'a K, &'a V)> {
+        self.index.get_key_value(i).into_iter().flat_map(move |(i, keys)| keys.iter().filter_map(move |k| {
+            match self.map.get_live(k) {
+                Some(v) if (self.indexer)(v) == *i => Some((k, v)),
+                _ => None,
+            }
+        }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        let start = std::time::Instant::now();
+        let scanned = self.map.len();
+        let dead : Vec<(K, V)> = self.map.drain_dead().collect();
+        for (k, v) in &dead {
+            self.unindex(k, v);
+        }
+        // drop keys the inner map removed on its own
+        let map = &self.map;
+        let indexer = &self.indexer;
+        self.index.retain(|i, keys| {
+            keys.retain(|k| map.get_live(k).map(|v| indexer(v) == *i).unwrap_or(false));
+            !keys.is_empty()
+        });
+        GcReport {
+            scanned,
+            collected: dead.len(),
+            elapsed:   start.elapsed(),
+        }
+    }
+}
+
+
+#[test]
+fn indexed_by_user() {
+    let mut sessions : IndexedHashMap<u32, (&'static str, u32), &'static str> = IndexedHashMap::new(|s: &(&'static str, u32)| s.0);
+    let (a, _)  = sessions.insert(1, ("alice", 0));
+    let (_b, _) = sessions.insert(2, ("alice", 0));
+    let (_c, _) = sessions.insert(3, ("bob", 0));
+
+    let mut alice : Vec<_> = sessions.iter_by_index(&"alice").map(|(k, _)| *k).collect();
+    alice.sort();
+    assert_eq!(alice, vec![1, 2]);
+
+    drop(a);
+    assert_eq!(sessions.get_by_index(&"alice").map(|(k, _)| *k), Some(2));
+
+    assert!(sessions.update(&2, |s| s.0 = "bob"));
+    assert_eq!(sessions.get_by_index(&"alice"), None);
+    assert_eq!(sessions.iter_by_index(&"bob").count(), 2);
+
+    // replacing moves the key to the new index value
+    let (_c, old) = sessions.insert(3, ("carol", 1));
+    assert_eq!(old, Some(("bob", 0)));
+    assert_eq!(sessions.iter_by_index(&"bob").map(|(k, _)| *k).collect::<Vec<_>>(), vec![2]);
+
+    assert_eq!(sessions.gc().collected, 1);
+    assert_eq!(sessions.index.len(), 2);
+
+    // the map can move to another thread
+    std::thread::spawn(move || sessions.len()).join().unwrap();
+}
@@ -0,0 +1,202 @@
+use std::ops::Deref;
+
+use super::atomic::AtomicUsize;
+use super::atomic::Ordering;
+use super::atomic::Arc;
+use super::mark::Marker;
+use super::mark::uncount;
+use super::GcReport;
+
+/// Liveness flag embedded in a value, so an `IntrusiveHashMap` does not have
+/// to allocate one per entry.
+pub struct EmbeddedMarker {
+    marker:  Marker,
+    // like mark ids, this stays outside of loom's model
+    claimed: std::sync::atomic::AtomicBool,
+}
+
+impl Default for EmbeddedMarker {
+    fn default() -> Self {
+        EmbeddedMarker {
+            marker:  Marker::new(),
+            claimed: std::sync::atomic::AtomicBool::new(false),
+        }
+    }
+}
+
+impl EmbeddedMarker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Values that carry their own marker. A value can be inserted only once,
+/// its marker is used up when the entry goes away.
+pub trait HasMarker {
+    fn marker(&self) -> &EmbeddedMarker;
+}
+
+/// Mark of an `IntrusiveHashMap` entry. It holds on to the value, so
+/// dropping the mark is what ends the entry, but it allocates nothing.
+pub struct IntrusiveMark<V: HasMarker> {
+    value: std::sync::Arc<V>,
+    gc:    Arc<AtomicUsize>,
+}
+
+impl<V: HasMarker> Deref for IntrusiveMark<V> {
+    type Target = V;
+    fn deref(&self) -> &V {
+        &self.value
+    }
+}
+
+impl<V: HasMarker> Drop for IntrusiveMark<V> {
+    fn drop(&mut self) {
+        // same order as MarkOnDrop, count before the entry turns dead
+        self.gc.fetch_add(1, Ordering::SeqCst);
+        if !self.value.marker().marker.kill() {
+            uncount(&self.gc);
+        }
+    }
+}
+
+/// Variant for values that are already shared behind an `Arc`, using the
+/// marker inside the value instead of allocating one per insert.
+pub struct IntrusiveHashMap<K, V> {
+    v:  std::collections::HashMap<K, std::sync::Arc<V>>,
+    gc: Arc<AtomicUsize>,
+}
+
+impl<K,V> Default for IntrusiveHashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    fn default() -> Self {
+        IntrusiveHashMap {
+            v:  std::collections::HashMap::new(),
+            gc: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl<K, V> IntrusiveHashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K,V> IntrusiveHashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash,
+          V: HasMarker,
+{
+    /// Insert `v` and return its mark, together with the value that was live
+    /// for `k` before.
+    ///
+    /// Panics if `v` was inserted before, into this or any other map.
+    pub fn insert(&mut self, k: K, v: std::sync::Arc<V>) -> (IntrusiveMark<V>, Option<std::sync::Arc<V>>) {
+        assert!(!v.marker().claimed.swap(true, std::sync::atomic::Ordering::SeqCst), "value was already inserted once");
+        self.maybe_gc();
+        self.remove_dead(&k);
+        let mark = IntrusiveMark{value: v.clone(), gc: self.gc.clone()};
+        let old = match self.v.insert(k, v) {
+            Some(old) if old.marker().marker.detach(&self.gc) => Some(old),
+            _ => None,
+        };
+        (mark, old)
+    }
+
+    pub fn get<Q>(&mut self, k: &Q) -> Option<&std::sync::Arc<V>>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.remove_dead(k);
+        self.v.get(k)
+    }
+
+    fn remove_dead<Q>(&mut self, k: &Q)
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        if self.v.get(k).map(|v| v.marker().marker.collect(&self.gc)).unwrap_or(false) {
+            self.v.remove(k);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.v.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    fn maybe_gc(&mut self) {
+        if self.gc.load(Ordering::SeqCst) > self.len() / 2 {
+            self.gc();
+        }
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        let start = std::time::Instant::now();
+        let scanned = self.v.len();
+        let gc = &self.gc;
+        self.v.retain(|_, v| !v.marker().marker.collect(gc));
+        GcReport {
+            scanned,
+            collected: scanned - self.v.len(),
+            elapsed:   start.elapsed(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &std::sync::Arc<V>)> {
+        self.v.iter().filter(|(_, v)| !v.marker().marker.is_dead())
+    }
+}
+
+
+#[test]
+fn intrusive_marker() {
+    struct Conn {
+        id:     u32,
+        marker: EmbeddedMarker,
+    }
+    impl HasMarker for Conn {
+        fn marker(&self) -> &EmbeddedMarker {
+            &self.marker
+        }
+    }
+    let conn = |id| std::sync::Arc::new(Conn{id, marker: EmbeddedMarker::new()});
+
+    let mut wm : IntrusiveHashMap<u32, Conn> = IntrusiveHashMap::new();
+    let (a, _) = wm.insert(1, conn(1));
+    let (b, _) = wm.insert(2, conn(2));
+    assert_eq!(a.id, 1);
+    assert_eq!(wm.get(&2).map(|c| c.id), Some(2));
+
+    drop(a);
+    assert!(wm.get(&1).is_none());
+    assert_eq!(wm.iter().map(|(k, _)| *k).collect::<Vec<_>>(), vec![2]);
+
+    let (_c, old) = wm.insert(2, conn(3));
+    assert_eq!(old.map(|c| c.id), Some(2));
+    drop(b);
+    assert_eq!(wm.gc.load(Ordering::SeqCst), 0);
+    assert_eq!(wm.get(&2).map(|c| c.id), Some(3));
+    assert_eq!(wm.len(), 1);
+}
+
+#[test]
+#[should_panic(expected = "already inserted")]
+fn intrusive_marker_reuse() {
+    struct Once(EmbeddedMarker);
+    impl HasMarker for Once {
+        fn marker(&self) -> &EmbeddedMarker {
+            &self.0
+        }
+    }
+    let v = std::sync::Arc::new(Once(EmbeddedMarker::new()));
+    let mut wm : IntrusiveHashMap<u32, Once> = IntrusiveHashMap::new();
+    let (_a, _) = wm.insert(1, v.clone());
+    wm.insert(2, v);
+}
@@ -1,26 +1,327 @@
 //#![feature(test)]
 //extern crate test;
 
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
+#[cfg(loom)]
+extern crate loom;
 
-pub struct MarkOnDrop {
-    marker: Arc<AtomicBool>,
-    gc:     Arc<AtomicUsize>,
+mod atomic;
+mod mark;
+use atomic::AtomicUsize;
+use atomic::Ordering;
+use atomic::Arc;
+use mark::Marker;
+use mark::MarkerPool;
+pub use mark::GcHandle;
+pub use mark::MarkOnDrop;
+pub use mark::WeakMark;
+use std::sync::mpsc;
+use std::time::Duration;
+use std::time::Instant;
+
+pub mod fixed;
+pub mod local;
+pub mod persist;
+pub mod slab;
+pub mod swap;
+pub mod sync;
+pub mod weak;
+
+mod bimap;
+mod btree;
+mod cow;
+mod cursor;
+mod deque;
+mod indexed;
+mod intrusive;
+mod lifetime;
+mod pacer;
+mod lru;
+mod ordered;
+mod priority;
+mod ptr;
+mod scope;
+mod setops;
+mod set;
+mod shared;
+mod snapshot;
+mod storage;
+mod weighted;
+pub use bimap::BiMap;
+pub use btree::BTreeMap;
+pub use cow::CowHashMap;
+pub use cursor::Cursor;
+pub use deque::VecDeque;
+pub use indexed::IndexedHashMap;
+pub use intrusive::EmbeddedMarker;
+pub use intrusive::HasMarker;
+pub use intrusive::IntrusiveHashMap;
+pub use intrusive::IntrusiveMark;
+pub use lifetime::LifetimeStats;
+pub use lru::LruHashMap;
+pub use ordered::OrderedHashMap;
+pub use priority::PriorityMap;
+pub use ptr::ByPtr;
+pub use scope::GcScope;
+pub use set::HashSet;
+pub use setops::KeySet;
+pub use shared::SharedHashMap;
+pub use slab::Slab;
+pub use snapshot::LiveView;
+pub use snapshot::Snapshot;
+pub use storage::GcLookup;
+pub use storage::GcMap;
+pub use storage::GcStorage;
+pub use storage::Stored;
+pub use weak::WeakKeyHashMap;
+pub use weak::WeakValueHashMap;
+pub use weighted::WeightedHashMap;
+
+#[derive(Clone, Copy, Default)]
+struct Config {
+    diagnostics: bool,
+    manual_gc:   bool,
+    lifetimes:   bool,
 }
 
-impl Drop for MarkOnDrop {
-    fn drop(&mut self) {
-        self.marker.store(true, Ordering::SeqCst);
-        self.gc.fetch_add(1, Ordering::SeqCst);
+/// Where and when the mark of an entry was created.
+struct Diagnostics {
+    created:   Instant,
+    #[cfg(feature = "backtrace")]
+    backtrace: std::backtrace::Backtrace,
+}
+
+impl Diagnostics {
+    fn capture() -> Self {
+        Diagnostics {
+            created:   Instant::now(),
+            #[cfg(feature = "backtrace")]
+            backtrace: std::backtrace::Backtrace::force_capture(),
+        }
+    }
+}
+
+/// A live entry whose mark has been alive for a suspiciously long time.
+#[derive(Debug)]
+pub struct Suspect<'a, K: 'a> {
+    pub key:       &'a K,
+    pub age:       Duration,
+    #[cfg(feature = "backtrace")]
+    pub backtrace: &'a std::backtrace::Backtrace,
+}
+
+/// What one sweep did, returned by `gc` and `gc_full`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Entries that were checked.
+    pub scanned:   usize,
+    /// Entries that were removed.
+    pub collected: usize,
+    pub elapsed:   Duration,
+}
+
+impl std::ops::AddAssign for GcReport {
+    fn add_assign(&mut self, other: GcReport) {
+        self.scanned   += other.scanned;
+        self.collected += other.collected;
+        self.elapsed   += other.elapsed;
+    }
+}
+
+/// What `insert_with_policy` does when the key already has an entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Replace {
+    /// Always insert. The old entry's mark no longer refers to anything.
+    Overwrite,
+    /// Only insert if there is no live entry for the key.
+    KeepExisting,
+    /// Only insert if there is a stored entry for the key and it is dead.
+    IfDead,
+}
+
+/// Estimated memory held by a map, see `HashMap::memory_usage`.
+///
+/// Heap memory owned by the keys and values themselves is not included.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryReport {
+    /// Bytes reserved by the table for keys, values and bookkeeping.
+    pub table_bytes:       usize,
+    /// Bytes of the shared markers of all stored entries.
+    pub marker_bytes:      usize,
+    /// Bytes of leak diagnostics, zero unless they were enabled.
+    pub diagnostics_bytes: usize,
+    /// Dead entries that are still stored.
+    pub tombstones:        usize,
+}
+
+impl MemoryReport {
+    pub fn total_bytes(&self) -> usize {
+        self.table_bytes + self.marker_bytes + self.diagnostics_bytes
+    }
+}
+
+struct Slot<V> {
+    value:       V,
+    marker:      Arc<Marker>,
+    deadline:    Option<Instant>,
+    diagnostics: Option<Box<Diagnostics>>,
+    // only recorded while lifetime tracking is on
+    inserted:    Option<Instant>,
+    pinned:      bool,
+    tags:        Box<[String]>,
+    // the gc counter the mark bumps, if it is not the one of the map the
+    // slot is stored in, i.e. the slot was moved over from another map.
+    counter:     Option<Arc<AtomicUsize>>,
+}
+
+impl<V> Slot<V> {
+    fn new(value: V, marker: Arc<Marker>, config: Config) -> Self {
+        Slot {
+            value,
+            marker,
+            deadline:    None,
+            diagnostics: if config.diagnostics { Some(Box::new(Diagnostics::capture())) } else { None },
+            inserted:    if config.lifetimes { Some(Instant::now()) } else { None },
+            pinned:      false,
+            tags:        Box::default(),
+            counter:     None,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        }
+    }
+
+    /// True once the mark dropped or the deadline passed, unless pinned.
+    fn is_dead(&self) -> bool {
+        !self.pinned && (self.marker.is_dead() || self.is_expired())
+    }
+
+    /// Check whether the slot may be removed, and if so make that final so
+    /// the entry can no longer be revived.
+    fn collect(&self, gc: &AtomicUsize) -> bool {
+        let gc = self.counter.as_deref().unwrap_or(gc);
+        if self.pinned {
+            // keeps a dropped mark from making every sweep look worthwhile
+            self.marker.park(gc);
+            false
+        } else if self.is_expired() {
+            self.marker.detach(gc);
+            true
+        } else {
+            self.marker.collect(gc)
+        }
+    }
+
+    /// Let go of the slot regardless of its mark. Returns true if it was live.
+    fn detach(&self, gc: &AtomicUsize) -> bool {
+        let gc = self.counter.as_deref().unwrap_or(gc);
+        if self.pinned {
+            self.marker.unpark(gc);
+        }
+        self.marker.detach(gc)
+    }
+}
+
+/// Sent to subscribers whenever the map collects an entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Evicted<K, V> {
+    pub key:   K,
+    /// Only set for subscriptions made with `subscribe_with_values`.
+    pub value: Option<V>,
+}
+
+/// One change to the set of stored keys, see `HashMap::subscribe_changes`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeEvent<K> {
+    /// An entry for the key was stored, replacing any previous one.
+    Inserted(K),
+    /// The entry for the key was removed from the map.
+    Removed(K),
+}
+
+struct Journal<K> {
+    tx:    mpsc::Sender<(u64, ChangeEvent<K>)>,
+    seq:   u64,
+    // subscribing needs K: Clone, recording does not
+    clone: fn(&K) -> K,
+}
+
+/// Everything that wants to hear about changes to the map.
+struct Hooks<K, V> {
+    events:    Option<(mpsc::SyncSender<Evicted<K, V>>, bool)>,
+    journal:   Option<Journal<K>>,
+    lifetimes: LifetimeStats,
+    // collected markers end up here, so new inserts take them from here too
+    pool:      MarkerPool,
+    teardown:  Option<Box<dyn FnMut(K, V) + Send + Sync>>,
+}
+
+impl<K, V> Default for Hooks<K, V> {
+    fn default() -> Self {
+        Hooks {
+            events:    None,
+            journal:   None,
+            lifetimes: LifetimeStats::default(),
+            pool:      MarkerPool::default(),
+            teardown:  None,
+        }
+    }
+}
+
+impl<K, V> Hooks<K, V> {
+    fn record(&mut self, event: fn(K) -> ChangeEvent<K>, key: &K) {
+        let disconnected = match self.journal {
+            Some(ref mut journal) => {
+                let sent = journal.tx.send((journal.seq, event((journal.clone)(key)))).is_ok();
+                journal.seq += 1;
+                !sent
+            },
+            None => false,
+        };
+        if disconnected {
+            self.journal = None;
+        }
+    }
+
+    fn inserted(&mut self, key: &K) {
+        self.record(ChangeEvent::Inserted, key);
+    }
+
+    fn removed(&mut self, key: &K) {
+        self.record(ChangeEvent::Removed, key);
+    }
+
+    fn collected(&mut self, key: K, slot: Slot<V>) {
+        self.removed(&key);
+        if let Some(inserted) = slot.inserted {
+            self.lifetimes.record(inserted.elapsed());
+        }
+        let disconnected = match self.events {
+            Some((ref tx, with_values)) => {
+                let value = if with_values { Some(slot.value) } else { None };
+                // never block the map owner, a full channel loses the event
+                matches!(tx.try_send(Evicted{key, value}), Err(mpsc::TrySendError::Disconnected(_)))
+            },
+            None => false,
+        };
+        if disconnected {
+            self.events = None;
+        }
+        self.pool.put(slot.marker);
     }
 }
 
 pub struct HashMap<K, V> {
-    v:  std::collections::HashMap<K, (V, Arc<AtomicBool>)>,
-    gc: Arc<AtomicUsize>,
+    v:       std::collections::HashMap<K, Slot<V>>,
+    gc:      Arc<AtomicUsize>,
+    // counters of slots that were moved in from other maps
+    adopted: Vec<Arc<AtomicUsize>>,
+    hooks:   Hooks<K, V>,
+    config:  Config,
+    pacer:   Option<pacer::Pacer>,
 }
 
 impl<K,V> Default for HashMap<K,V>
@@ -28,8 +329,12 @@ impl<K,V> Default for HashMap<K,V>
 {
     fn default() -> Self {
         HashMap {
-            v:  std::collections::HashMap::new(),
-            gc: Arc::new(AtomicUsize::new(0)),
+            v:       std::collections::HashMap::new(),
+            gc:      Arc::new(AtomicUsize::new(0)),
+            adopted: Vec::new(),
+            hooks:   Hooks::default(),
+            config:  Config::default(),
+            pacer:   None,
         }
     }
 }
@@ -40,25 +345,139 @@ impl<K, V> HashMap<K, V>
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// A map that never sweeps on its own, dead entries stay stored until
+    /// `gc` is called. Looking up a dead key still removes that one entry.
+    pub fn with_manual_gc() -> Self {
+        let mut map = Self::default();
+        map.config.manual_gc = true;
+        map
+    }
+
+    /// A map whose sweep threshold follows how expensive its sweeps are and
+    /// how fast dead entries pile up, instead of the fixed half of `len`.
+    ///
+    /// Sweeps still scan the whole map, so their pause grows with it. Once
+    /// a sweep takes about `target` the map waits for more dead entries,
+    /// up to as many as it stores, so that such pauses come less often.
+    /// Below that it sweeps earlier than a plain map does.
+    pub fn with_adaptive_gc(target: Duration) -> Self {
+        let mut map = Self::default();
+        map.pacer = Some(pacer::Pacer::new(target));
+        map
+    }
+
+    /// Build a map from `iter`, returning the marks for all inserted entries.
+    pub fn from_iter_with_marks<I>(iter: I) -> (Self, Vec<MarkOnDrop>)
+        where I: IntoIterator<Item = (K, V)>,
+    {
+        let mut map = Self::new();
+        let mut marks = Vec::new();
+        map.extend_with(iter, |mark| marks.push(mark));
+        (map, marks)
+    }
+
+    /// Adopt a populated std map, handing the mark of every entry to `sink`
+    /// together with its key. Entries whose mark `sink` drops are dead.
+    pub fn from_std<S, F>(std: std::collections::HashMap<K, V, S>, mut sink: F) -> Self
+        where F: FnMut(&K, MarkOnDrop),
+    {
+        let mut map = Self::new();
+        map.v.reserve(std.len());
+        for (k, v) in std {
+            let mark = MarkOnDrop::new(&map.gc);
+            let slot = Slot::new(v, mark.marker.clone(), map.config);
+            sink(&k, mark);
+            map.v.insert(k, slot);
+        }
+        map
+    }
 }
 
 
 impl<K,V> HashMap<K,V>
     where K: std::cmp::Eq + std::hash::Hash
 {
-    pub fn insert(&mut self, k: K, v: V) -> (MarkOnDrop, Option<V>)
-        where K: std::cmp::Eq + std::hash::Hash
+    pub fn insert(&mut self, k: K, v: V) -> (MarkOnDrop, Option<V>) {
+        self.insert_slot(k, v, None)
+    }
+
+    /// Insert an entry that is also collected once `ttl` has passed, even
+    /// if its mark is still alive.
+    pub fn insert_with_ttl(&mut self, k: K, v: V, ttl: Duration) -> (MarkOnDrop, Option<V>) {
+        self.insert_slot(k, v, Some(Instant::now() + ttl))
+    }
+
+    /// Insert according to `policy`. Whenever an entry is replaced its value
+    /// is returned, dead or not, so whatever it holds can be cleaned up.
+    pub fn insert_with_policy(&mut self, k: K, v: V, policy: Replace) -> Result<(MarkOnDrop, Option<V>), Rejected<K, V>> {
+        let dead = self.v.get(&k).map(Slot::is_dead);
+        let allowed = match policy {
+            Replace::Overwrite    => true,
+            Replace::KeepExisting => dead != Some(false),
+            Replace::IfDead       => dead == Some(true),
+        };
+        if !allowed {
+            return Err(Rejected{key: k, value: v});
+        }
+        // taken out before the sweep could collect it
+        let old = self.v.remove(&k).map(|old| {
+            old.detach(&self.gc);
+            self.hooks.pool.put(old.marker);
+            old.value
+        });
+        self.maybe_gc();
+        let mark = self.hooks.pool.mark(&self.gc);
+        let slot = Slot::new(v, mark.marker.clone(), self.config);
+        self.hooks.inserted(&k);
+        self.v.insert(k, slot);
+        Ok((mark, old))
+    }
+
+    /// Insert like `insert`, but hand the mark to `f` and return the guard
+    /// it builds around it instead.
+    pub fn insert_with_handle<H, F>(&mut self, k: K, v: V, f: F) -> (H, Option<V>)
+        where H: GcHandle,
+              F: FnOnce(MarkOnDrop) -> H,
+    {
+        let (mark, old) = self.insert(k, v);
+        (f(mark), old)
+    }
+
+    /// Insert an entry that can be removed together with every other entry
+    /// sharing one of its tags, see `invalidate_tag`.
+    pub fn insert_tagged<I>(&mut self, k: K, v: V, tags: I) -> (MarkOnDrop, Option<V>)
+        where I: IntoIterator,
+              I::Item: Into<String>,
     {
+        let tags : Box<[String]> = tags.into_iter().map(Into::into).collect();
         self.maybe_gc();
-        let mark = MarkOnDrop {
-            marker: Arc::new(AtomicBool::new(false)),
-            gc:     self.gc.clone(),
-        };
-        let old = match self.v.insert(k, (v, mark.marker.clone())) {
+        self.put_with(k, v, |slot| slot.tags = tags)
+    }
+
+    fn insert_slot(&mut self, k: K, v: V, deadline: Option<Instant>) -> (MarkOnDrop, Option<V>) {
+        self.maybe_gc();
+        self.put(k, v, deadline)
+    }
+
+    // insert without the gc threshold check, bulk inserts do that once upfront
+    fn put(&mut self, k: K, v: V, deadline: Option<Instant>) -> (MarkOnDrop, Option<V>) {
+        self.put_with(k, v, |slot| slot.deadline = deadline)
+    }
+
+    fn put_with<F>(&mut self, k: K, v: V, f: F) -> (MarkOnDrop, Option<V>)
+        where F: FnOnce(&mut Slot<V>),
+    {
+        self.remove_dead(&k);
+        let mark = self.hooks.pool.mark(&self.gc);
+        let mut slot = Slot::new(v, mark.marker.clone(), self.config);
+        f(&mut slot);
+        self.hooks.inserted(&k);
+        let old = match self.v.insert(k, slot) {
             None => None,
-            Some((v, marker)) => {
-                if marker.load(Ordering::SeqCst) == false {
-                    Some(v)
+            Some(old) => {
+                if (old.detach(&self.gc) && !old.is_expired()) || old.pinned {
+                    Some(old.value)
                 } else {
                     None
                 }
@@ -67,192 +486,1848 @@ impl<K,V> HashMap<K,V>
         (mark, old)
     }
 
-    pub fn get<Q: ?Sized>(&mut self, k: &Q) -> Option<&V>
-        where Q: std::cmp::Eq + std::hash::Hash,
-              K: std::borrow::Borrow<Q>,
-    {
-        let remove = if let Some((_, marker)) = self.v.get(k) {
-            marker.load(Ordering::SeqCst)
-        } else {
-            false
-        };
+    /// Insert with the mark owned by `scope`, the entry lives until the scope drops.
+    pub fn insert_scoped(&mut self, scope: &mut GcScope, k: K, v: V) -> Option<V> {
+        let (mark, old) = self.insert(k, v);
+        scope.push(mark);
+        old
+    }
 
-        if remove {
-            self.v.remove(k);
-        }
+    /// Reserve room for `additional` more entries, reporting failure instead
+    /// of aborting. Dead entries are swept first if that is due anyway.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), std::collections::TryReserveError> {
+        self.maybe_gc();
+        self.v.try_reserve(additional)
+    }
 
-        self.v.get(k).map(|(v,_)|v)
+    /// Like `insert`, but fails instead of aborting if the table cannot grow.
+    /// The marker of the entry is still allocated infallibly.
+    pub fn insert_fallible(&mut self, k: K, v: V) -> Result<(MarkOnDrop, Option<V>), std::collections::TryReserveError> {
+        self.try_reserve(1)?;
+        Ok(self.put(k, v, None))
     }
 
-    pub fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
-        where Q: std::cmp::Eq + std::hash::Hash,
-              K: std::borrow::Borrow<Q>,
+    /// Insert only if there is no live entry for `k`. A dead entry is replaced.
+    pub fn try_insert(&mut self, k: K, v: V) -> Result<(MarkOnDrop, &mut V), OccupiedError<'_, K, V>> {
+        match self.entry(k) {
+            Entry::Occupied(entry) => Err(OccupiedError{entry, value: v}),
+            Entry::Vacant(entry) => {
+                let mut mark = None;
+                let v = entry.insert_with(|m|{
+                    mark = Some(m);
+                    v
+                });
+                Ok((mark.unwrap(), v))
+            }
+        }
+    }
+
+    /// Return the live value for `k`, inserting `f()` if there is none.
+    /// The mark is only returned when an insert actually happened.
+    pub fn get_or_insert_with<F>(&mut self, k: K, f: F) -> (&mut V, Option<MarkOnDrop>)
+        where F: FnOnce() -> V,
     {
-        let remove = if let Some((_, marker)) = self.v.get(k) {
-            marker.load(Ordering::SeqCst)
-        } else {
-            false
-        };
+        match self.entry(k) {
+            Entry::Occupied(entry) => (entry.into_mut(), None),
+            Entry::Vacant(entry) => {
+                let mut mark = None;
+                let v = entry.insert_with(|m|{
+                    mark = Some(m);
+                    f()
+                });
+                (v, mark)
+            }
+        }
+    }
 
-        if remove {
-            self.v.remove(k);
+    /// Insert every pair from `iter`, handing each new mark to `sink`.
+    pub fn extend_with<I, F>(&mut self, iter: I, mut sink: F)
+        where I: IntoIterator<Item = (K, V)>,
+              F: FnMut(MarkOnDrop),
+    {
+        let iter = iter.into_iter();
+        self.maybe_gc();
+        self.v.reserve(iter.size_hint().0);
+        for (k, v) in iter {
+            let (mark, _) = self.put(k, v, None);
+            sink(mark);
         }
+    }
 
-        self.v.get_mut(k).map(|(v,_)|v)
+    /// Insert every pair from `iter`, returning what `insert` would have for
+    /// each. Capacity is reserved and the gc threshold checked only once.
+    pub fn insert_many<I>(&mut self, iter: I) -> Vec<(MarkOnDrop, Option<V>)>
+        where I: IntoIterator<Item = (K, V)>,
+    {
+        let iter = iter.into_iter();
+        self.maybe_gc();
+        self.v.reserve(iter.size_hint().0);
+        iter.map(|(k, v)| self.put(k, v, None)).collect()
     }
 
-    pub fn len(&self) -> usize {
-        self.v.len()
+    pub fn get<Q>(&mut self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.remove_dead(k);
+        self.v.get(k).map(|slot| &slot.value)
     }
 
+    pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.remove_dead(k);
+        self.v.get_mut(k).map(|slot| &mut slot.value)
+    }
 
-    fn maybe_gc(&mut self) {
-        if self.gc.load(Ordering::SeqCst) > self.len() / 2 {
-            self.gc();
+    fn remove_dead<Q>(&mut self, k: &Q)
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        if self.v.get(k).map(|slot| slot.collect(&self.gc)).unwrap_or(false) {
+            if let Some((k, slot)) = self.v.remove_entry(k) {
+                self.hooks.collected(k, slot);
+            }
         }
     }
 
-    pub fn gc(&mut self) {
-        self.gc.store(0, Ordering::SeqCst);
-        //TODO to make gc more efficient, there should be multiple gc flags marking "regions"
-        //but for that we need to modify the hashmap iterator
-        self.v.retain(|_, (_, marker)| {
-            !marker.load(Ordering::SeqCst)
-        })
+    /// Lookup through a shared reference. Dead entries are skipped but
+    /// cannot be removed here, that is left to the next `&mut` access.
+    fn get_live<Q>(&self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        match self.v.get(k) {
+            Some(slot) if !slot.is_dead() => Some(&slot.value),
+            _ => None,
+        }
     }
 
+    /// A cursor before the first entry, for passes that both modify and remove.
+    pub fn cursor(&mut self) -> Cursor<'_, K, V>
+        where K: Clone,
+    {
+        Cursor::new(self)
+    }
 
-    pub fn entry(&mut self, k: K) -> Entry<K, V> {
-        self.maybe_gc();
-
-        let remove = if let Some((_, marker)) = self.v.get(&k) {
-            marker.load(Ordering::SeqCst)
-        } else {
-            false
-        };
+    /// True if there is a live entry for `k`. Never removes anything.
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.get_live(k).is_some()
+    }
 
-        if remove {
-            self.v.remove(&k);
+    /// Keep the entry for `k` even after its mark dropped or its ttl passed,
+    /// until it is unpinned or replaced. Returns false if there is no live entry.
+    pub fn pin<Q>(&mut self, k: &Q) -> bool
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.remove_dead(k);
+        match self.v.get_mut(k) {
+            Some(slot) => {
+                slot.pinned = true;
+                true
+            },
+            None => false,
         }
+    }
 
-        match self.v.entry(k) {
-            std::collections::hash_map::Entry::Occupied(n) => {
-                Entry::Occupied(OccupiedEntry{n})
-            },
-            std::collections::hash_map::Entry::Vacant(n) => {
-                Entry::Vacant(VacantEntry{n, gc: self.gc.clone()})
+    /// Make a pinned entry collectable again. If its mark already dropped,
+    /// or it never had one, it is dead from now on. Returns false if the
+    /// entry was not pinned.
+    pub fn unpin<Q>(&mut self, k: &Q) -> bool
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        match self.v.get_mut(k) {
+            Some(slot) if slot.pinned => {
+                slot.pinned = false;
+                let gc = slot.counter.as_ref().unwrap_or(&self.gc);
+                slot.marker.unpark(gc);
+                // nothing else refers to the marker, so no mark will drop
+                if Arc::strong_count(&slot.marker) == 1 {
+                    gc.fetch_add(1, Ordering::SeqCst);
+                    if !slot.marker.kill() {
+                        mark::uncount(gc);
+                    }
+                }
+                true
             },
+            _ => false,
         }
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
-        self.v.iter().filter_map(|(k, (v,marker))|{
-            if marker.load(Ordering::SeqCst) == true {
-                None
-            } else {
-                Some((k,v))
-            }
-        })
+    /// Key of the live entry created together with the mark `id`.
+    /// Walks the whole map.
+    pub fn key_for(&self, id: u64) -> Option<&K> {
+        self.v.iter().find(|(_, slot)| slot.marker.id == id && !slot.is_dead()).map(|(k, _)| k)
     }
 
-    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
-        self.v.iter_mut().filter_map(|(k, (v,marker))|{
-            if marker.load(Ordering::SeqCst) == true {
-                None
-            } else {
-                Some((k,v))
-            }
-        })
+    /// Remove the live entry created together with the mark `id`. The mark
+    /// stays valid but no longer refers to anything. Walks the whole map.
+    pub fn remove_by_id(&mut self, id: u64) -> Option<(K, V)> {
+        let (k, slot) = self.v.extract_if(|_, slot| slot.marker.id == id && !slot.is_dead()).next()?;
+        slot.detach(&self.gc);
+        self.hooks.removed(&k);
+        Some((k, slot.value))
     }
 
-}
+    /// Remove every entry inserted with `tag`, pinned ones included. Their
+    /// marks stay valid but no longer refer to anything. Removed entries
+    /// are reported to subscribers like collected ones. Walks the whole map.
+    pub fn invalidate_tag(&mut self, tag: &str) -> usize {
+        let gc = &self.gc;
+        let hooks = &mut self.hooks;
+        let mut n = 0;
+        for (k, slot) in self.v.extract_if(|_, slot| slot.tags.iter().any(|t| t == tag)) {
+            slot.detach(gc);
+            hooks.collected(k, slot);
+            n += 1;
+        }
+        n
+    }
 
+    /// Number of stored entries, including dead ones that were not collected
+    /// yet. This is what the table holds, use `live_len` for what `get` sees.
+    pub fn len(&self) -> usize {
+        self.v.len()
+    }
 
-pub struct OccupiedEntry<'a, K: 'a, V: 'a>{
-    n: std::collections::hash_map::OccupiedEntry<'a, K, (V,Arc<AtomicBool>)>,
-}
+    /// True if there is no live entry, i.e. `live_len` is 0. Dead entries
+    /// may still be stored. Walks the map until it finds a live entry.
+    pub fn is_empty(&self) -> bool {
+        self.v.values().all(Slot::is_dead)
+    }
 
-pub struct VacantEntry<'a, K: 'a, V: 'a>{
-    n: std::collections::hash_map::VacantEntry<'a, K, (V,Arc<AtomicBool>)>,
-    gc: Arc<AtomicUsize>,
-}
+    /// Number of entries `get` would return. Walks the whole map.
+    pub fn live_len(&self) -> usize {
+        self.v.len() - self.dead_len()
+    }
 
-pub enum Entry<'a, K: 'a, V: 'a> {
-    /// An occupied entry.
-    Occupied(OccupiedEntry<'a, K, V>),
+    /// Number of dead entries still stored, i.e. what the next `gc` removes.
+    /// Walks the whole map, since expired entries are not counted anywhere.
+    pub fn dead_len(&self) -> usize {
+        self.v.values().filter(|slot| slot.is_dead()).count()
+    }
 
-    /// A vacant entry.
-    Vacant(VacantEntry<'a, K, V>),
-}
+    /// Record when each new mark is created, and with the `backtrace`
+    /// feature where, so long lived guards can be found with `suspects`.
+    pub fn set_leak_diagnostics(&mut self, on: bool) {
+        self.config.diagnostics = on;
+    }
 
+    /// Record when each new entry is inserted, so `lifetime_stats` can tell
+    /// how long entries lived once they are collected.
+    pub fn set_lifetime_tracking(&mut self, on: bool) {
+        self.config.lifetimes = on;
+    }
 
-impl<'a, K, V> OccupiedEntry<'a, K, V> {
-    pub fn into_mut(self) -> &'a mut V {
-        &mut self.n.into_mut().0
+    /// Lifetimes of the entries collected so far that were inserted while
+    /// lifetime tracking was on.
+    pub fn lifetime_stats(&self) -> &LifetimeStats {
+        &self.hooks.lifetimes
     }
-}
 
-impl<'a, K, V> VacantEntry<'a, K, V> {
-    pub fn insert_with<F: FnOnce(MarkOnDrop) -> V>(self, value: F) -> &'a mut V {
-        let mark = MarkOnDrop {
-            marker: Arc::new(AtomicBool::new(false)),
-            gc:     self.gc.clone(),
-        };
-        let marker = mark.marker.clone();
-        &mut (self.n.insert((value(mark), marker)).0)
+    /// Estimate how much memory the map holds. Walks the whole map.
+    pub fn memory_usage(&self) -> MemoryReport {
+        // one control byte per bucket, like the std table
+        let bucket = std::mem::size_of::<(K, Slot<V>)>() + 1;
+        // an Arc allocation is the strong and weak count followed by the marker
+        let marker = 2 * std::mem::size_of::<usize>() + std::mem::size_of::<Marker>();
+        let diagnostics = self.v.values().filter(|slot| slot.diagnostics.is_some()).count();
+        MemoryReport {
+            table_bytes:       self.v.capacity() * bucket,
+            marker_bytes:      self.v.len() * marker,
+            diagnostics_bytes: diagnostics * std::mem::size_of::<Diagnostics>(),
+            tombstones:        self.dead_len(),
+        }
     }
-}
 
-impl<'a, K, V> Entry<'a, K, V> {
-    pub fn or_insert_with<F: FnOnce(MarkOnDrop) -> V>(self, default: F) -> &'a mut V {
-        match self {
-            Entry::Occupied(entry)  => entry.into_mut(),
-            Entry::Vacant(entry)    => {
-                entry.insert_with(default)
+    /// Panic if the bookkeeping is inconsistent: every stored entry has its
+    /// own marker that was not collected, and the gc counter matches the
+    /// number of entries whose mark dropped, apart from entries moved in by
+    /// `split_off` or `merge`, dead entries still held by a clone and pinned
+    /// ones a sweep parked. Marks
+    /// dropped on other threads while this runs can make it fail spuriously.
+    #[cfg(debug_assertions)]
+    pub fn assert_invariants(&self) {
+        let mut markers = std::collections::HashSet::new();
+        let mut dead = 0;
+        for slot in self.v.values() {
+            assert!(markers.insert(&*slot.marker as *const Marker), "marker shared between two entries");
+            assert!(!slot.marker.is_collected(), "collected entry is still stored");
+            // moved in slots count towards the counter of their old map
+            if slot.marker.is_dead() && !slot.marker.is_parked() && slot.counter.is_none() {
+                dead += 1;
             }
         }
+        let pending = self.gc.load(Ordering::SeqCst);
+        assert!(pending <= self.v.len(), "gc counter {} exceeds {} stored entries", pending, self.v.len());
+        assert_eq!(pending, dead, "gc counter does not match the dead entries");
     }
-}
-
 
+    /// Live entries whose mark was created more than `older_than` ago.
+    /// Only entries inserted while leak diagnostics were on are considered.
+    pub fn suspects(&self, older_than: Duration) -> impl Iterator<Item = Suspect<'_, K>> {
+        let now = Instant::now();
+        self.v.iter().filter_map(move |(key, slot)|{
+            let diagnostics = match slot.diagnostics {
+                Some(ref diagnostics) if !slot.is_dead() => diagnostics,
+                _ => return None,
+            };
+            let age = now.duration_since(diagnostics.created);
+            if age < older_than {
+                return None;
+            }
+            Some(Suspect {
+                key,
+                age,
+                #[cfg(feature = "backtrace")]
+                backtrace: &diagnostics.backtrace,
+            })
+        })
+    }
 
-#[test]
-fn iter() {
-    let mut wm : HashMap<u32, u8> = HashMap::new();
-    let (mark, _) = wm.insert(1, 7);
-    drop(mark);
-    let (mark, _) = wm.insert(2, 8);
+    /// Receive the key of every entry the map collects from now on,
+    /// replacing any previous subscription. Events are dropped rather than
+    /// blocking the map when more than `bound` are pending.
+    pub fn subscribe(&mut self, bound: usize) -> mpsc::Receiver<Evicted<K, V>> {
+        let (tx, rx) = mpsc::sync_channel(bound);
+        self.hooks.events = Some((tx, false));
+        rx
+    }
 
-    let mut iter = wm.iter();
-    assert_eq!(iter.next(), Some((&2, &8)));
-    assert_eq!(iter.next(), None);
-}
+    /// Receive every change to the set of stored keys from now on, numbered
+    /// in order, replacing any previous journal subscription. It starts
+    /// with an `Inserted` event for every entry stored right now, so a
+    /// replica can rebuild the set exactly by applying events in order.
+    /// Unlike `subscribe` the channel is unbounded, losing an event would
+    /// leave the replica wrong.
+    pub fn subscribe_changes(&mut self) -> mpsc::Receiver<(u64, ChangeEvent<K>)>
+        where K: Clone,
+    {
+        let (tx, rx) = mpsc::channel();
+        self.hooks.journal = Some(Journal{tx, seq: 0, clone: K::clone});
+        let hooks = &mut self.hooks;
+        for k in self.v.keys() {
+            hooks.inserted(k);
+        }
+        rx
+    }
 
+    /// Call `f` with every live entry that is still stored when the map is
+    /// dropped, e.g. to release what the values refer to at shutdown.
+    /// Dead entries are dropped without it.
+    pub fn set_teardown<F>(&mut self, f: F)
+        where F: FnMut(K, V) + Send + Sync + 'static,
+    {
+        self.hooks.teardown = Some(Box::new(f));
+    }
 
-#[test]
-fn entry() {
-    let mut wm : HashMap<u32, u8> = HashMap::new();
+    /// Like `subscribe`, but events also carry the collected value.
+    pub fn subscribe_with_values(&mut self, bound: usize) -> mpsc::Receiver<Evicted<K, V>> {
+        let (tx, rx) = mpsc::sync_channel(bound);
+        self.hooks.events = Some((tx, true));
+        rx
+    }
 
-    let mut holdme = None;
 
-    {
-        let val = wm.entry(1).or_insert_with(|mark|{
-            holdme = Some(mark);
-            2
-        });
-        *val = 3;
+    /// Whether the automatic sweep in `insert` and `entry` would run now,
+    /// or would if the map was not created `with_manual_gc`.
+    pub fn needs_gc(&self) -> bool {
+        let threshold = match self.pacer {
+            Some(ref pacer) => pacer.threshold(self.len()),
+            None => self.len() / 2,
+        };
+        self.pending() > threshold
     }
 
-    assert_eq!(wm.get(&1), Some(&3));
-    drop(holdme);
-    assert_eq!(wm.get(&1), None);
+    /// Fraction of stored entries whose mark dropped, from 0.0 to 1.0.
+    /// Expired entries are not included.
+    pub fn gc_pressure(&self) -> f64 {
+        if self.v.is_empty() {
+            return 0.0;
+        }
+        (self.pending() as f64 / self.len() as f64).min(1.0)
+    }
 
-    {
-        let val = wm.entry(1).or_insert_with(|mark|2);
-        *val = 3;
+    // counters of moved in slots are shared with their old map, so this can
+    // overestimate until both maps swept.
+    fn pending(&self) -> usize {
+        self.adopted.iter().fold(self.gc.load(Ordering::SeqCst), |n, gc| n + gc.load(Ordering::SeqCst))
     }
 
-    assert_eq!(wm.get(&1), None);
+    fn maybe_gc(&mut self) {
+        if !self.config.manual_gc && self.needs_gc() {
+            self.gc();
+        }
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        //TODO to make gc more efficient, there should be multiple gc flags marking "regions"
+        //but for that we need to modify the hashmap iterator
+        let start = Instant::now();
+        let scanned = self.v.len();
+        let gc = &self.gc;
+        let hooks = &mut self.hooks;
+        for (k, slot) in self.v.extract_if(|_, slot| slot.collect(gc)) {
+            hooks.collected(k, slot);
+        }
+        self.swept(scanned, start)
+    }
+
+    // bookkeeping shared by every sweep of the whole map
+    fn swept(&mut self, scanned: usize, start: Instant) -> GcReport {
+        // a counter only we hold has no marks or slots left behind it
+        self.adopted.retain(|gc| Arc::strong_count(gc) > 1);
+        let report = GcReport {
+            scanned,
+            collected: scanned - self.v.len(),
+            elapsed:   start.elapsed(),
+        };
+        if let Some(ref mut pacer) = self.pacer {
+            pacer.swept(&report, self.v.len());
+        }
+        report
+    }
+
+
+    /// Sweep like `gc`, but hand the collected entries back to the caller
+    /// instead of dropping them. They are not reported to subscribers, but
+    /// are recorded in the change journal.
+    pub fn drain_dead(&mut self) -> impl Iterator<Item = (K, V)> {
+        let gc = &self.gc;
+        let hooks = &mut self.hooks;
+        let dead : Vec<(K, V)> = self.v.extract_if(|_, slot| slot.collect(gc))
+            .map(|(k, slot)| {
+                hooks.removed(&k);
+                (k, slot.value)
+            })
+            .collect();
+        dead.into_iter()
+    }
+
+    /// Move every entry whose key matches `f` into a new map. Outstanding
+    /// marks stay valid and keep their entries alive in the new map.
+    pub fn split_off<F>(&mut self, mut f: F) -> Self
+        where F: FnMut(&K) -> bool,
+    {
+        // marks keep bumping the counter they were created with, which both
+        // maps now share, so this map continues on a fresh counter.
+        let old = std::mem::replace(&mut self.gc, Arc::new(AtomicUsize::new(0)));
+        let mut other = HashMap::default();
+        other.config = self.config;
+        for (k, slot) in self.v.extract_if(|k, _| f(k)) {
+            self.hooks.removed(&k);
+            other.adopt(k, slot, &old);
+        }
+        for slot in self.v.values_mut() {
+            if slot.counter.is_none() {
+                slot.counter = Some(old.clone());
+            }
+        }
+        self.adopted.push(old);
+        other
+    }
+
+    /// Move all entries of `other` into this map, replacing live entries for
+    /// the same keys. Outstanding marks of `other` stay valid.
+    pub fn merge(&mut self, mut other: HashMap<K, V>) {
+        let from = other.gc.clone();
+        self.v.reserve(other.v.len());
+        for (k, slot) in other.v.drain() {
+            self.adopt(k, slot, &from);
+        }
+    }
+
+    fn adopt(&mut self, k: K, mut slot: Slot<V>, from: &Arc<AtomicUsize>) {
+        let counter = slot.counter.take().unwrap_or_else(|| from.clone());
+        if !Arc::ptr_eq(&counter, &self.gc) {
+            if !self.adopted.iter().any(|gc| Arc::ptr_eq(gc, &counter)) {
+                self.adopted.push(counter.clone());
+            }
+            slot.counter = Some(counter);
+        }
+        self.hooks.inserted(&k);
+        if let Some(old) = self.v.insert(k, slot) {
+            // invalidate the outstanding mark
+            old.detach(&self.gc);
+        }
+    }
+
+    pub fn entry(&mut self, k: K) -> Entry<'_, K, V> {
+        self.maybe_gc();
+        self.remove_dead(&k);
+
+        match self.v.entry(k) {
+            std::collections::hash_map::Entry::Occupied(n) => {
+                Entry::Occupied(OccupiedEntry{n})
+            },
+            std::collections::hash_map::Entry::Vacant(n) => {
+                Entry::Vacant(VacantEntry{n, gc: self.gc.clone(), config: self.config, hooks: &mut self.hooks})
+            },
+        }
+    }
+
+    /// Like `entry`, but takes the key by reference and only converts it
+    /// into an owned `K` when a value actually gets inserted.
+    pub fn entry_ref<'a, 'b, Q>(&'a mut self, k: &'b Q) -> EntryRef<'a, 'b, K, Q, V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.maybe_gc();
+
+        match self.v.get(k).map(Slot::is_dead) {
+            Some(false) => {
+                let v = &mut self.v.get_mut(k).unwrap().value;
+                EntryRef::Occupied(OccupiedEntryRef{v})
+            },
+            Some(true) => {
+                self.remove_dead(k);
+                EntryRef::Vacant(VacantEntryRef{map: self, k})
+            },
+            None => {
+                EntryRef::Vacant(VacantEntryRef{map: self, k})
+            },
+        }
+    }
+
+    /// Borrow the live entries as a read-only map, without copying them.
+    pub fn as_inner(&self) -> LiveView<'_, K, V> {
+        LiveView::new(&self.v)
+    }
+
+    /// Unwrap into a plain map of the live entries. Outstanding marks no
+    /// longer do anything.
+    pub fn into_std(mut self) -> std::collections::HashMap<K, V> {
+        // taken out, so the teardown hook does not see these entries
+        let v = std::mem::take(&mut self.v);
+        let gc = &self.gc;
+        v.into_iter().filter_map(|(k, slot)|{
+            let dead = slot.is_dead();
+            slot.detach(gc);
+            if dead {
+                None
+            } else {
+                Some((k, slot.value))
+            }
+        }).collect()
+    }
+
+    /// Copy the live entries into a frozen view that can be shared across
+    /// threads while this map keeps changing.
+    pub fn snapshot(&self) -> Snapshot<K, V>
+        where K: Clone,
+              V: Clone,
+    {
+        Snapshot::new(self.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+    }
+
+    /// Clone the live values into a `Vec`, sweeping dead entries first.
+    pub fn snapshot_values(&mut self) -> Vec<V>
+        where V: Clone,
+    {
+        self.gc();
+        self.iter().map(|(_, v)| v.clone()).collect()
+    }
+
+    /// Clone the live entries into a `Vec`, sweeping dead entries first.
+    pub fn snapshot_pairs(&mut self) -> Vec<(K, V)>
+        where K: Clone,
+              V: Clone,
+    {
+        self.gc();
+        self.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.v.iter().filter_map(|(k, slot)|{
+            if slot.is_dead() {
+                None
+            } else {
+                Some((k, &slot.value))
+            }
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+        self.v.iter_mut().filter_map(|(k, slot)|{
+            if slot.is_dead() {
+                None
+            } else {
+                Some((k, &mut slot.value))
+            }
+        })
+    }
+
+    /// Like `gc`, but the liveness checks are spread over one scoped thread
+    /// per cpu. Each thread hands back the keys it collected, which are then
+    /// removed on the caller.
+    pub fn gc_full(&mut self) -> GcReport
+        where K: Clone + Send + Sync,
+              V: Sync,
+    {
+        let start = Instant::now();
+        let scanned = self.v.len();
+        let gc = &self.gc;
+        let collected = par_filter_map(self.v.iter().collect(), |(k, slot)| {
+            if slot.collect(gc) {
+                Some(k.clone())
+            } else {
+                None
+            }
+        });
+        for k in collected {
+            if let Some(slot) = self.v.remove(&k) {
+                self.hooks.collected(k, slot);
+            }
+        }
+        self.swept(scanned, start)
+    }
+
+    /// Call `f` on every live entry, spread over one scoped thread per cpu.
+    pub fn par_for_each<F>(&self, f: F)
+        where K: Sync,
+              V: Sync,
+              F: Fn(&K, &V) + Sync,
+    {
+        par_chunks(self.iter().collect(), |(k, v)| f(k, v));
+    }
+
+    /// Like `par_for_each`, with mutable access to the values.
+    pub fn par_for_each_mut<F>(&mut self, f: F)
+        where K: Sync,
+              V: Send,
+              F: Fn(&K, &mut V) + Sync,
+    {
+        par_chunks(self.iter_mut().collect(), |(k, v)| f(k, v));
+    }
+
+}
+
+fn par_chunks<T, F>(items: Vec<T>, f: F)
+    where T: Send,
+          F: Fn(T) + Sync,
+{
+    par_filter_map(items, |t| -> Option<()> {
+        f(t);
+        None
+    });
+}
+
+// every thread fills its own vec, they are only joined at the end
+fn par_filter_map<T, R, F>(items: Vec<T>, f: F) -> Vec<R>
+    where T: Send,
+          R: Send,
+          F: Fn(T) -> Option<R> + Sync,
+{
+    let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    if threads < 2 || items.len() < 2 {
+        return items.into_iter().filter_map(f).collect();
+    }
+
+    let chunk = items.len().div_ceil(threads);
+    let f = &f;
+    let mut items = items.into_iter();
+    std::thread::scope(|s| {
+        let mut parts = Vec::new();
+        loop {
+            let part : Vec<T> = items.by_ref().take(chunk).collect();
+            if part.is_empty() {
+                break;
+            }
+            parts.push(s.spawn(move || part.into_iter().filter_map(f).collect::<Vec<R>>()));
+        }
+        parts.into_iter().flat_map(|part| part.join().unwrap()).collect()
+    })
+}
+
+/// The clone shares the markers of the original, so dropping a mark removes
+/// its entry from both maps. Dead entries are not copied. Replacing or
+/// removing an entry in one map leaves the other one alone.
+///
+/// The inherited entries count towards the gc counter of the original, which
+/// the clone adopts like after `merge`.
+impl<K, V> Clone for HashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash + Clone,
+          V: Clone,
+{
+    fn clone(&self) -> Self {
+        let mut map = HashMap::default();
+        map.config = self.config;
+        map.pacer = self.pacer.clone();
+        map.v.reserve(self.v.len());
+        for (k, slot) in &self.v {
+            if !slot.is_dead() {
+                map.adopt(k.clone(), slot.clone(), &self.gc);
+            }
+        }
+        map
+    }
+}
+
+impl<K, V> Drop for HashMap<K, V> {
+    fn drop(&mut self) {
+        // let go of every marker, a clone may still hold some of them
+        let mut teardown = self.hooks.teardown.take();
+        for (k, slot) in self.v.drain() {
+            let live = !slot.is_dead();
+            slot.detach(&self.gc);
+            if let (true, Some(teardown)) = (live, teardown.as_mut()) {
+                teardown(k, slot.value);
+            }
+        }
+    }
+}
+
+// the clone holds the marker as well, it must be let go through `collect`
+// or `detach` like the original
+impl<V: Clone> Clone for Slot<V> {
+    fn clone(&self) -> Self {
+        self.marker.share();
+        Slot {
+            value:       self.value.clone(),
+            marker:      self.marker.clone(),
+            deadline:    self.deadline,
+            diagnostics: None,
+            inserted:    self.inserted,
+            pinned:      self.pinned,
+            tags:        self.tags.clone(),
+            counter:     self.counter.clone(),
+        }
+    }
+}
+
+/// Maps are equal when their live entries are, tombstones are ignored.
+impl<K, V> PartialEq for HashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash,
+          V: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().count() == other.iter().count() &&
+            self.iter().all(|(k, v)| other.get_live(k) == Some(v))
+    }
+}
+
+impl<K, V> Eq for HashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash,
+          V: Eq,
+{}
+
+impl<K, V> std::fmt::Debug for HashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash + std::fmt::Debug,
+          V: std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let dead = self.v.values().filter(|slot| slot.is_dead()).count();
+        f.debug_struct("HashMap")
+            .field("live", &DebugLive(self))
+            .field("dead", &dead)
+            .field("pending_gc", &self.pending())
+            .finish()
+    }
+}
+
+struct DebugLive<'a, K: 'a, V: 'a>(&'a HashMap<K, V>);
+
+impl<'a, K, V> std::fmt::Debug for DebugLive<'a, K, V>
+    where K: std::cmp::Eq + std::hash::Hash + std::fmt::Debug,
+          V: std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_map().entries(self.0.iter()).finish()
+    }
+}
+
+impl<K, Q, V> std::ops::Index<&Q> for HashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash + std::borrow::Borrow<Q>,
+          Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+{
+    type Output = V;
+
+    /// Panics if there is no live entry for the key.
+    fn index(&self, k: &Q) -> &V {
+        self.get_live(k).expect("no live entry found for key")
+    }
+}
+
+pub struct OccupiedEntry<'a, K: 'a, V: 'a>{
+    n: std::collections::hash_map::OccupiedEntry<'a, K, Slot<V>>,
+}
+
+pub struct VacantEntry<'a, K: 'a, V: 'a>{
+    n: std::collections::hash_map::VacantEntry<'a, K, Slot<V>>,
+    gc: Arc<AtomicUsize>,
+    config: Config,
+    hooks: &'a mut Hooks<K, V>,
+}
+
+pub enum Entry<'a, K: 'a, V: 'a> {
+    /// An occupied entry.
+    Occupied(OccupiedEntry<'a, K, V>),
+
+    /// A vacant entry.
+    Vacant(VacantEntry<'a, K, V>),
+}
+
+
+impl<'a, K, V> OccupiedEntry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        self.n.key()
+    }
+
+    pub fn get(&self) -> &V {
+        &self.n.get().value
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.n.get_mut().value
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self.n.into_mut().value
+    }
+}
+
+impl<'a, K, V> VacantEntry<'a, K, V> {
+    /// The key that would be used when inserting through this entry.
+    pub fn key(&self) -> &K {
+        self.n.key()
+    }
+
+    /// Take ownership of the key without inserting anything.
+    pub fn into_key(self) -> K {
+        self.n.into_key()
+    }
+
+    pub fn insert_with<F: FnOnce(MarkOnDrop) -> V>(self, value: F) -> &'a mut V {
+        let mark = self.hooks.pool.mark(&self.gc);
+        let marker = mark.marker.clone();
+        self.hooks.inserted(self.n.key());
+        &mut self.n.insert(Slot::new(value(mark), marker, self.config)).value
+    }
+}
+
+impl<'a, K, V> Entry<'a, K, V> {
+    pub fn or_insert_with<F: FnOnce(MarkOnDrop) -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry)  => entry.into_mut(),
+            Entry::Vacant(entry)    => {
+                entry.insert_with(default)
+            }
+        }
+    }
+}
+
+
+/// Returned by `try_insert` when the key already holds a live entry.
+pub struct OccupiedError<'a, K: 'a, V: 'a> {
+    /// The entry that is already in the map.
+    pub entry: OccupiedEntry<'a, K, V>,
+    /// The value that was not inserted.
+    pub value: V,
+}
+
+impl<'a, K, V> std::fmt::Debug for OccupiedError<'a, K, V>
+    where K: std::fmt::Debug, V: std::fmt::Debug
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("OccupiedError")
+            .field("key", self.entry.key())
+            .field("old_value", self.entry.get())
+            .field("new_value", &self.value)
+            .finish()
+    }
+}
+
+impl<'a, K, V> std::fmt::Display for OccupiedError<'a, K, V>
+    where K: std::fmt::Debug, V: std::fmt::Debug
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "failed to insert {:?}, key {:?} already holds live value {:?}",
+               self.value, self.entry.key(), self.entry.get())
+    }
+}
+
+impl<'a, K, V> std::error::Error for OccupiedError<'a, K, V>
+    where K: std::fmt::Debug, V: std::fmt::Debug
+{}
+
+/// Returned by `insert_with_policy` when the policy did not allow the insert.
+#[derive(Debug)]
+pub struct Rejected<K, V> {
+    pub key:   K,
+    /// The value that was not inserted.
+    pub value: V,
+}
+
+impl<K, V> std::fmt::Display for Rejected<K, V>
+    where K: std::fmt::Debug
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "insert for key {:?} rejected by the replacement policy", self.key)
+    }
+}
+
+impl<K, V> std::error::Error for Rejected<K, V>
+    where K: std::fmt::Debug, V: std::fmt::Debug
+{}
+
+pub struct OccupiedEntryRef<'a, V: 'a>{
+    v: &'a mut V,
+}
+
+pub struct VacantEntryRef<'a, 'b, K: 'a, Q: 'b + ?Sized, V: 'a>{
+    map: &'a mut HashMap<K, V>,
+    k: &'b Q,
+}
+
+pub enum EntryRef<'a, 'b, K: 'a, Q: 'b + ?Sized, V: 'a> {
+    /// An occupied entry.
+    Occupied(OccupiedEntryRef<'a, V>),
+
+    /// A vacant entry.
+    Vacant(VacantEntryRef<'a, 'b, K, Q, V>),
+}
+
+impl<'a, V> OccupiedEntryRef<'a, V> {
+    pub fn get(&self) -> &V {
+        self.v
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        self.v
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        self.v
+    }
+}
+
+impl<'a, 'b, K, Q: ?Sized, V> VacantEntryRef<'a, 'b, K, Q, V> {
+    /// The borrowed key that would be converted and used when inserting.
+    pub fn key(&self) -> &'b Q {
+        self.k
+    }
+
+    pub fn insert_with<F: FnOnce(MarkOnDrop) -> V>(self, value: F) -> &'a mut V
+        where K: std::cmp::Eq + std::hash::Hash + From<&'b Q>,
+    {
+        let mark = self.map.hooks.pool.mark(&self.map.gc);
+        let marker = mark.marker.clone();
+        let k = K::from(self.k);
+        self.map.hooks.inserted(&k);
+        &mut self.map.v.entry(k).or_insert(Slot::new(value(mark), marker, self.map.config)).value
+    }
+}
+
+impl<'a, 'b, K, Q: ?Sized, V> EntryRef<'a, 'b, K, Q, V> {
+    pub fn or_insert_with<F: FnOnce(MarkOnDrop) -> V>(self, default: F) -> &'a mut V
+        where K: std::cmp::Eq + std::hash::Hash + From<&'b Q>,
+    {
+        match self {
+            EntryRef::Occupied(entry)  => entry.into_mut(),
+            EntryRef::Vacant(entry)    => {
+                entry.insert_with(default)
+            }
+        }
+    }
+}
+
+
+
+#[test]
+fn iter() {
+    let mut wm : HashMap<u32, u8> = HashMap::new();
+    let (mark, _) = wm.insert(1, 7);
+    drop(mark);
+    let (_mark, _) = wm.insert(2, 8);
+
+    let mut iter = wm.iter();
+    assert_eq!(iter.next(), Some((&2, &8)));
+    assert_eq!(iter.next(), None);
+}
+
+
+#[test]
+fn entry() {
+    let mut wm : HashMap<u32, u8> = HashMap::new();
+
+    let mut holdme = None;
+
+    {
+        let val = wm.entry(1).or_insert_with(|mark|{
+            holdme = Some(mark);
+            2
+        });
+        *val = 3;
+    }
+
+    assert_eq!(wm.get(&1), Some(&3));
+    drop(holdme);
+    assert_eq!(wm.get(&1), None);
+
+    {
+        let val = wm.entry(1).or_insert_with(|_|2);
+        *val = 3;
+    }
+
+    assert_eq!(wm.get(&1), None);
+}
+
+#[test]
+fn vacant_key() {
+    let mut wm : HashMap<String, String> = HashMap::new();
+    let mut holdme = None;
+
+    match wm.entry("hello".to_string()) {
+        Entry::Occupied(_) => panic!("expected vacant entry"),
+        Entry::Vacant(entry) => {
+            let name = format!("{} world", entry.key());
+            entry.insert_with(|mark|{
+                holdme = Some(mark);
+                name
+            });
+        }
+    }
+    assert_eq!(wm.get("hello"), Some(&"hello world".to_string()));
+
+    match wm.entry("bye".to_string()) {
+        Entry::Occupied(_) => panic!("expected vacant entry"),
+        Entry::Vacant(entry) => assert_eq!(entry.into_key(), "bye"),
+    }
+    assert_eq!(wm.get("bye"), None);
+}
+
+#[test]
+fn entry_ref() {
+    let mut wm : HashMap<String, u8> = HashMap::new();
+    let mut holdme = None;
+
+    {
+        let val = wm.entry_ref("hello").or_insert_with(|mark|{
+            holdme = Some(mark);
+            2
+        });
+        *val = 3;
+    }
+    assert_eq!(wm.get("hello"), Some(&3));
+
+    match wm.entry_ref("hello") {
+        EntryRef::Occupied(entry) => assert_eq!(entry.get(), &3),
+        EntryRef::Vacant(_)       => panic!("expected occupied entry"),
+    }
+
+    drop(holdme);
+    match wm.entry_ref("hello") {
+        EntryRef::Occupied(_)     => panic!("expected vacant entry"),
+        EntryRef::Vacant(entry)   => assert_eq!(entry.key(), "hello"),
+    }
+}
+
+#[test]
+fn try_insert() {
+    let mut wm : HashMap<u32, u8> = HashMap::new();
+
+    let (mark, v) = wm.try_insert(1, 2).unwrap();
+    *v = 3;
+
+    match wm.try_insert(1, 4) {
+        Ok(_)    => panic!("live entry was overwritten"),
+        Err(err) => {
+            assert_eq!(err.entry.get(), &3);
+            assert_eq!(err.value, 4);
+        }
+    }
+
+    drop(mark);
+    let (_mark, v) = wm.try_insert(1, 5).unwrap();
+    assert_eq!(*v, 5);
+}
+
+#[test]
+fn index() {
+    let mut wm : HashMap<u32, u8> = HashMap::new();
+    let (_mark, _) = wm.insert(1, 7);
+    assert_eq!(wm[&1], 7);
+}
+
+#[test]
+#[should_panic(expected = "no live entry found for key")]
+fn index_dead() {
+    let mut wm : HashMap<u32, u8> = HashMap::new();
+    let (mark, _) = wm.insert(1, 7);
+    drop(mark);
+    let _ = wm[&1];
+}
+
+#[test]
+fn extend_with() {
+    let mut wm : HashMap<u32, u8> = HashMap::new();
+    let mut marks = Vec::new();
+    wm.extend_with((0..10).map(|i|(i, i as u8)), |mark| marks.push(mark));
+
+    assert_eq!(marks.len(), 10);
+    assert_eq!(wm.get(&3), Some(&3));
+    drop(marks);
+    assert_eq!(wm.get(&3), None);
+}
+
+#[test]
+fn from_iter_with_marks() {
+    let (mut wm, mut marks) = HashMap::from_iter_with_marks(vec![(1, "a"), (2, "b")]);
+    assert_eq!(marks.len(), 2);
+    assert_eq!(wm.get(&1), Some(&"a"));
+    assert_eq!(wm.get(&2), Some(&"b"));
+
+    marks.clear();
+    assert_eq!(wm.get(&1), None);
+}
+
+#[test]
+fn debug() {
+    let mut wm : HashMap<u32, u8> = HashMap::new();
+    let (mark, _) = wm.insert(1, 7);
+    let (_mark, _) = wm.insert(2, 8);
+    drop(mark);
+    assert_eq!(format!("{:?}", wm), "HashMap { live: {2: 8}, dead: 1, pending_gc: 1 }");
+}
+
+#[test]
+fn clone_shares_markers() {
+    let mut wm : HashMap<u32, u8> = HashMap::new();
+    let (mark, _) = wm.insert(1, 7);
+    let (_mark, _) = wm.insert(2, 8);
+
+    let mut other = wm.clone();
+    assert_eq!(other.get(&1), Some(&7));
+    *other.get_mut(&2).unwrap() = 9;
+    assert_eq!(wm.get(&2), Some(&8));
+
+    drop(mark);
+    assert_eq!(wm.get(&1), None);
+    assert_eq!(other.get(&1), None);
+}
+
+#[test]
+fn eq_ignores_dead() {
+    let mut a : HashMap<u32, u8> = HashMap::new();
+    let mut b : HashMap<u32, u8> = HashMap::new();
+    let (_ma, _) = a.insert(1, 7);
+    let (_mb, _) = b.insert(1, 7);
+    let (mark, _) = b.insert(2, 8);
+    assert_ne!(a, b);
+
+    drop(mark);
+    assert_eq!(a, b);
+
+    let (_mb, _) = b.insert(1, 9);
+    assert_ne!(a, b);
+}
+
+#[test]
+fn par_for_each() {
+    let mut wm : HashMap<u32, usize> = HashMap::new();
+    let mut marks : Vec<MarkOnDrop> = (0..1000).map(|i| wm.insert(i, i as usize).0).collect();
+    marks.truncate(500);
+
+    wm.par_for_each_mut(|_, v| *v *= 2);
+
+    let sum = AtomicUsize::new(0);
+    wm.par_for_each(|_, v| { sum.fetch_add(*v, Ordering::SeqCst); });
+    assert_eq!(sum.load(Ordering::SeqCst), (0..500).map(|i| i * 2).sum());
+}
+
+#[test]
+fn ttl() {
+    let mut wm : HashMap<u32, u8> = HashMap::new();
+    let (_a, _) = wm.insert_with_ttl(1, 7, Duration::from_secs(3600));
+    let (_b, _) = wm.insert_with_ttl(2, 8, Duration::from_millis(10));
+    assert_eq!(wm.get(&2), Some(&8));
+
+    std::thread::sleep(Duration::from_millis(20));
+    assert_eq!(wm.get(&1), Some(&7));
+    assert_eq!(wm.iter().count(), 1);
+    wm.gc();
+    assert_eq!(wm.len(), 1);
+}
+
+#[test]
+fn scoped() {
+    let mut a : HashMap<u32, u8> = HashMap::new();
+    let mut b : HashMap<&'static str, u8> = HashMap::new();
+    let mut session = GcScope::new();
+
+    a.insert_scoped(&mut session, 1, 1);
+    a.insert_scoped(&mut session, 2, 2);
+    b.insert_scoped(&mut session, "x", 3);
+    let (_other, _) = a.insert(3, 3);
+    assert_eq!(session.len(), 3);
+    assert_eq!(a.get(&1), Some(&1));
+
+    drop(session);
+    assert_eq!(a.get(&1), None);
+    assert_eq!(a.get(&2), None);
+    assert_eq!(b.get("x"), None);
+    assert_eq!(a.get(&3), Some(&3));
+}
+
+#[test]
+fn subscribe() {
+    let mut wm : HashMap<u32, &'static str> = HashMap::new();
+    let events = wm.subscribe_with_values(16);
+    let (a, _) = wm.insert(1, "a");
+    let (b, _) = wm.insert(2, "b");
+    let (_c, _) = wm.insert(3, "c");
+
+    drop(a);
+    assert_eq!(wm.get(&1), None);
+    assert_eq!(events.try_recv(), Ok(Evicted{key: 1, value: Some("a")}));
+
+    drop(b);
+    wm.gc();
+    assert_eq!(events.try_recv(), Ok(Evicted{key: 2, value: Some("b")}));
+    assert!(events.try_recv().is_err());
+
+    let keys = wm.subscribe(1);
+    drop(events);
+    let (d, _) = wm.insert(4, "d");
+    drop(d);
+    let (_d, _) = wm.insert(4, "e");
+    assert_eq!(keys.try_recv(), Ok(Evicted{key: 4, value: None}));
+}
+
+#[test]
+fn weak_mark() {
+    let mut wm : HashMap<u32, u8> = HashMap::new();
+    let (mark, _) = wm.insert(1, 7);
+    let (_keep, _) = wm.insert(2, 8);
+
+    let weak = mark.downgrade();
+    assert_eq!(wm.iter().count(), 1);
+    let mark = weak.upgrade().unwrap();
+    assert!(weak.clone().upgrade().is_none());
+    assert_eq!(wm.get(&1), Some(&7));
+
+    let weak = mark.downgrade();
+    wm.gc();
+    assert!(weak.upgrade().is_none());
+    assert_eq!(wm.get(&1), None);
+}
+
+#[test]
+fn get_or_insert_with() {
+    let mut wm : HashMap<u32, u8> = HashMap::new();
+
+    let (v, mark) = wm.get_or_insert_with(1, || 7);
+    *v += 1;
+    assert!(mark.is_some());
+
+    let (v, again) = wm.get_or_insert_with(1, || unreachable!());
+    assert_eq!(*v, 8);
+    assert!(again.is_none());
+
+    drop(mark);
+    let (v, mark) = wm.get_or_insert_with(1, || 9);
+    assert_eq!(*v, 9);
+    assert!(mark.is_some());
+}
+
+#[test]
+fn suspects() {
+    let mut wm : HashMap<u32, u8> = HashMap::new();
+    let (_untracked, _) = wm.insert(1, 1);
+    wm.set_leak_diagnostics(true);
+    let (_leaked, _) = wm.insert(2, 2);
+    let (dropped, _) = wm.insert(3, 3);
+    drop(dropped);
+
+    std::thread::sleep(Duration::from_millis(20));
+    let (_fresh, _) = wm.insert(4, 4);
+
+    let suspects : Vec<_> = wm.suspects(Duration::from_millis(10)).map(|s| *s.key).collect();
+    assert_eq!(suspects, vec![2]);
+}
+
+#[test]
+fn insert_many() {
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    let (_a, _) = wm.insert(1, 0);
+    let mut inserted = wm.insert_many((0..100).map(|i| (i, i * 2)));
+    assert_eq!(inserted.len(), 100);
+    assert_eq!(inserted[1].1, Some(0));
+    assert_eq!(wm.get(&50), Some(&100));
+
+    inserted.truncate(50);
+    assert_eq!(wm.get(&50), None);
+    assert_eq!(wm.get(&1), Some(&2));
+}
+
+#[test]
+fn drain_dead() {
+    let mut wm : HashMap<u32, String> = HashMap::new();
+    let (a, _) = wm.insert(1, "a".to_string());
+    let (_b, _) = wm.insert(2, "b".to_string());
+    drop(a);
+
+    let dead : Vec<_> = wm.drain_dead().collect();
+    assert_eq!(dead, vec![(1, "a".to_string())]);
+    assert_eq!(wm.len(), 1);
+    assert_eq!(wm.drain_dead().count(), 0);
+}
+
+#[test]
+fn dead_count() {
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    let pending = |wm: &HashMap<u32, u32>| wm.gc.load(Ordering::SeqCst);
+
+    // a replaced entry is gone already, its old mark must not count
+    let (old, _) = wm.insert(1, 10);
+    let (_new, _) = wm.insert(1, 11);
+    drop(old);
+    assert_eq!(pending(&wm), 0);
+
+    // lazy removal takes back what the drop counted
+    let (mark, _) = wm.insert(2, 20);
+    drop(mark);
+    assert_eq!(pending(&wm), 1);
+    assert_eq!(wm.get(&2), None);
+    assert_eq!(pending(&wm), 0);
+
+    // so does replacing a dead entry, and reviving one
+    let (mark, _) = wm.insert(3, 30);
+    drop(mark);
+    let (_mark, _) = wm.insert(3, 31);
+    assert_eq!(pending(&wm), 0);
+    let (mark, _) = wm.insert(4, 40);
+    let weak = mark.downgrade();
+    assert_eq!(pending(&wm), 1);
+    let _mark = weak.upgrade().unwrap();
+    assert_eq!(pending(&wm), 0);
+}
+
+#[test]
+fn live_len() {
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    let (a, _) = wm.insert(1, 10);
+    let (_b, _) = wm.insert(2, 20);
+    let (_c, _) = wm.insert(3, 30);
+    drop(a);
+    assert_eq!((wm.len(), wm.live_len(), wm.dead_len()), (3, 2, 1));
+
+    // replacing a live entry changes nothing, replacing a dead one revives the key
+    let (_b, _) = wm.insert(2, 21);
+    let (_a, _) = wm.insert(1, 11);
+    assert_eq!((wm.len(), wm.live_len(), wm.dead_len()), (3, 3, 0));
+
+    let (d, _) = wm.insert(4, 40);
+    drop(d);
+    assert_eq!(wm.get(&4), None);
+    assert_eq!((wm.len(), wm.live_len(), wm.dead_len()), (3, 3, 0));
+
+    let (_e, _) = wm.insert_with_ttl(5, 50, Duration::from_millis(0));
+    assert_eq!((wm.len(), wm.live_len(), wm.dead_len()), (4, 3, 1));
+}
+
+#[test]
+fn needs_gc() {
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    assert!(wm.is_empty());
+    assert_eq!(wm.gc_pressure(), 0.0);
+
+    let mut marks : Vec<MarkOnDrop> = (0..4).map(|i| wm.insert(i, i).0).collect();
+    assert!(!wm.is_empty());
+    marks.truncate(1);
+    assert!(wm.needs_gc());
+    assert_eq!(wm.gc_pressure(), 0.75);
+
+    drop(marks);
+    assert!(wm.is_empty());
+    wm.gc();
+    assert!(!wm.needs_gc());
+    assert_eq!(wm.len(), 0);
+}
+
+#[test]
+fn memory_usage() {
+    let mut wm : HashMap<u32, u64> = HashMap::new();
+    assert_eq!(wm.memory_usage().total_bytes(), 0);
+
+    let (a, _) = wm.insert(1, 1);
+    let (_b, _) = wm.insert(2, 2);
+    drop(a);
+    let report = wm.memory_usage();
+    assert!(report.table_bytes >= 2 * (4 + 8));
+    assert!(report.marker_bytes >= 2 * 2 * std::mem::size_of::<usize>());
+    assert_eq!(report.diagnostics_bytes, 0);
+    assert_eq!(report.tombstones, 1);
+
+    wm.set_leak_diagnostics(true);
+    let (_c, _) = wm.insert(3, 3);
+    assert!(wm.memory_usage().diagnostics_bytes > 0);
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn assert_invariants() {
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    let (a, _) = wm.insert(1, 1);
+    let (b, _) = wm.insert(2, 2);
+    let (_c, _) = wm.insert(1, 3);
+    drop(a);
+    drop(b);
+    wm.assert_invariants();
+
+    wm.get(&2);
+    let (_d, _) = wm.insert(4, 4);
+    wm.assert_invariants();
+    wm.gc();
+    wm.assert_invariants();
+}
+
+#[test]
+fn manual_gc() {
+    let mut wm : HashMap<u32, u32> = HashMap::with_manual_gc();
+    let marks : Vec<MarkOnDrop> = (0..10).map(|i| wm.insert(i, i).0).collect();
+    drop(marks);
+    let (_mark, _) = wm.insert(10, 10);
+    assert!(wm.needs_gc());
+    assert_eq!(wm.dead_len(), 10);
+
+    wm.gc();
+    assert_eq!(wm.len(), 1);
+}
+
+#[test]
+fn pin() {
+    let mut wm : HashMap<&'static str, u32> = HashMap::new();
+    let (route, _) = wm.insert("/", 1);
+    let (other, _) = wm.insert("/tmp", 2);
+    assert!(wm.pin(&"/"));
+    drop(route);
+    drop(other);
+    assert!(!wm.pin(&"/tmp"));
+
+    wm.gc();
+    assert_eq!(wm.get(&"/"), Some(&1));
+    assert_eq!(wm.live_len(), 1);
+
+    assert!(wm.unpin(&"/"));
+    assert!(!wm.unpin(&"/"));
+    assert_eq!(wm.get(&"/"), None);
+    assert_eq!(wm.len(), 0);
+}
+
+#[test]
+fn mark_id() {
+    let mut wm : HashMap<u32, &'static str> = HashMap::new();
+    let (a, _) = wm.insert(1, "a");
+    let (b, _) = wm.insert(2, "b");
+    assert_ne!(a.id(), b.id());
+    assert_eq!(wm.key_for(b.id()), Some(&2));
+
+    assert_eq!(wm.remove_by_id(a.id()), Some((1, "a")));
+    assert_eq!(wm.remove_by_id(a.id()), None);
+    assert_eq!(wm.key_for(a.id()), None);
+
+    let id = b.id();
+    let weak = b.downgrade();
+    assert_eq!(weak.id(), id);
+    assert_eq!(wm.key_for(id), None);
+}
+
+#[test]
+fn contains_key() {
+    let mut wm : HashMap<String, u32> = HashMap::new();
+    let (mark, _) = wm.insert("a".to_string(), 1);
+    assert!(wm.contains_key("a"));
+    drop(mark);
+    assert!(!wm.contains_key("a"));
+    assert_eq!(wm.len(), 1);
+}
+
+#[test]
+fn gc_full() {
+    let mut wm : HashMap<u32, u32> = HashMap::with_manual_gc();
+    let mut marks : Vec<MarkOnDrop> = (0..1000).map(|i| wm.insert(i, i).0).collect();
+    let (pinned, _) = wm.insert(1000, 1000);
+    wm.pin(&1000);
+    marks.retain(|mark| mark.id() % 3 == 0);
+    drop(pinned);
+
+    wm.gc_full();
+    assert_eq!(wm.len(), marks.len() + 1);
+    assert_eq!(wm.live_len(), wm.len());
+    // the pinned entry no longer counts
+    assert_eq!(wm.gc.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn try_reserve() {
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    assert!(wm.try_reserve(usize::MAX).is_err());
+    assert!(wm.try_reserve(16).is_ok());
+    assert!(wm.memory_usage().table_bytes > 0);
+
+    let (_mark, old) = wm.insert_fallible(1, 1).unwrap();
+    assert_eq!(old, None);
+    assert_eq!(wm.get(&1), Some(&1));
+}
+
+#[test]
+fn gc_report() {
+    let mut wm : HashMap<u32, u32> = HashMap::with_manual_gc();
+    let mut marks : Vec<MarkOnDrop> = (0..10).map(|i| wm.insert(i, i).0).collect();
+    marks.truncate(6);
+
+    let report = wm.gc();
+    assert_eq!((report.scanned, report.collected), (10, 4));
+    drop(marks);
+    let report = wm.gc_full();
+    assert_eq!((report.scanned, report.collected), (6, 6));
+}
+
+#[test]
+fn split_off() {
+    let mut wm : HashMap<u32, u32> = HashMap::with_manual_gc();
+    let mut marks : Vec<MarkOnDrop> = (0..10).map(|i| wm.insert(i, i).0).collect();
+    let (fresh, _) = wm.insert(10, 10);
+
+    let mut odd = wm.split_off(|k| k % 2 == 1);
+    assert_eq!((wm.len(), odd.len()), (6, 5));
+
+    // marks made before the split still work for both halves
+    marks.truncate(4);
+    assert_eq!(wm.get(&6), None);
+    assert_eq!(odd.get(&7), None);
+    assert_eq!(odd.get(&1), Some(&1));
+    assert_eq!(odd.gc().collected, 2);
+    assert_eq!(wm.gc().collected, 2);
+    assert_eq!(wm.pending(), 0);
+
+    wm.merge(odd);
+    assert_eq!(wm.live_len(), 5);
+    drop(marks);
+    drop(fresh);
+    assert!(wm.needs_gc());
+    assert_eq!(wm.gc().collected, 5);
+    assert_eq!(wm.pending(), 0);
+    assert!(wm.adopted.is_empty());
+}
+
+#[test]
+fn from_std() {
+    let std : std::collections::HashMap<u32, &'static str> = vec![(1, "a"), (2, "b"), (3, "c")].into_iter().collect();
+    let mut marks = std::collections::HashMap::new();
+    let mut wm = HashMap::from_std(std, |k, mark| if *k != 2 { marks.insert(*k, mark); });
+    assert_eq!(marks.len(), 2);
+    assert_eq!(wm.get(&1), Some(&"a"));
+    assert_eq!(wm.get(&2), None);
+
+    marks.remove(&3);
+    assert_eq!(wm.get(&3), None);
+    assert_eq!(wm.len(), 1);
+}
+
+#[test]
+fn invalidate_tag() {
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    let events = wm.subscribe(8);
+    let (a, _)  = wm.insert_tagged(1, 10, vec!["upstream-a"]);
+    let (_b, _) = wm.insert_tagged(2, 20, vec!["upstream-a", "upstream-b"]);
+    let (_c, _) = wm.insert_tagged(3, 30, vec!["upstream-b"]);
+    let (_d, _) = wm.insert(4, 40);
+    wm.pin(&2);
+
+    assert_eq!(wm.invalidate_tag("upstream-a"), 2);
+    assert_eq!(wm.get(&1), None);
+    assert_eq!(wm.get(&2), None);
+    assert_eq!(events.try_iter().count(), 2);
+
+    // the guard no longer refers to anything, dropping it is not counted
+    drop(a);
+    assert_eq!(wm.pending(), 0);
+    assert_eq!(wm.invalidate_tag("upstream-a"), 0);
+    assert_eq!(wm.invalidate_tag("upstream-b"), 1);
+    assert_eq!(wm.len(), 1);
+}
+
+#[test]
+fn gc_handle() {
+    struct Lease {
+        mark:    MarkOnDrop,
+        _permit: std::sync::Arc<()>,
+    }
+    impl GcHandle for Lease {
+        fn mark(&self) -> &MarkOnDrop {
+            &self.mark
+        }
+    }
+
+    let permits = std::sync::Arc::new(());
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    let (lease, _) = wm.insert_with_handle(1, 10, |mark| Lease{mark, _permit: permits.clone()});
+    let boxed : Box<dyn GcHandle> = Box::new(lease);
+    assert_eq!(wm.key_for(boxed.id()), Some(&1));
+    assert_eq!(std::sync::Arc::strong_count(&permits), 2);
+
+    drop(boxed);
+    assert_eq!(wm.get(&1), None);
+    assert_eq!(std::sync::Arc::strong_count(&permits), 1);
+}
+
+#[test]
+fn mark_raw() {
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    let (mark, _) = wm.insert(1, 10);
+    let id = mark.id();
+    let raw = mark.into_raw();
+    assert_eq!(wm.get(&1), Some(&10));
+
+    let mark = unsafe { MarkOnDrop::from_raw(raw) };
+    assert_eq!(mark.id(), id);
+    drop(mark);
+    assert_eq!(wm.get(&1), None);
+}
+
+#[test]
+fn change_journal() {
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    let (a, _)  = wm.insert(1, 10);
+    let changes = wm.subscribe_changes();
+    let (_b, _) = wm.insert(2, 20);
+    let _c = wm.get_or_insert_with(3, || 30).1;
+    drop(a);
+    wm.gc();
+    let (_d, _) = wm.insert(2, 21);
+
+    let events : Vec<_> = changes.try_iter().collect();
+    assert_eq!(events.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(), (0..events.len() as u64).collect::<Vec<_>>());
+    assert_eq!(events[0].1, ChangeEvent::Inserted(1));
+    assert!(events.contains(&(3, ChangeEvent::Removed(1))));
+
+    let mut replica = std::collections::HashSet::new();
+    for (_, event) in events {
+        match event {
+            ChangeEvent::Inserted(k) => replica.insert(k),
+            ChangeEvent::Removed(k)  => replica.remove(&k),
+        };
+    }
+    let mut live : Vec<_> = wm.iter().map(|(k, _)| *k).collect();
+    let mut replica : Vec<_> = replica.into_iter().collect();
+    live.sort();
+    replica.sort();
+    assert_eq!(replica, live);
+}
+
+#[test]
+fn lifetime_stats() {
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    let (a, _) = wm.insert(0, 0);
+    wm.set_lifetime_tracking(true);
+    assert_eq!(wm.lifetime_stats().p50(), None);
+
+    let mut marks : Vec<MarkOnDrop> = (1..100).map(|i| wm.insert(i, i).0).collect();
+    drop(a);
+    std::thread::sleep(Duration::from_millis(20));
+    marks.truncate(1);
+    wm.gc();
+
+    let stats = wm.lifetime_stats();
+    assert_eq!(stats.count(), 98);
+    assert!(stats.p50().unwrap() >= Duration::from_millis(16));
+    assert!(stats.p99().unwrap() <= stats.max().unwrap());
+    assert!(stats.mean().unwrap() >= Duration::from_millis(20));
+}
+
+#[test]
+fn adaptive_gc() {
+    // sweeps far below the target pause come early
+    let mut wm : HashMap<u32, u32> = HashMap::with_adaptive_gc(Duration::from_secs(10));
+    let mut marks : Vec<MarkOnDrop> = (0..1000).map(|i| wm.insert(i, i).0).collect();
+    wm.gc();
+    marks.truncate(900);
+    assert!(wm.needs_gc());
+
+    // sweeps far above it wait for more dead entries than a plain map
+    let mut wm : HashMap<u32, u32> = HashMap::with_adaptive_gc(Duration::from_nanos(1));
+    let mut marks : Vec<MarkOnDrop> = (0..1000).map(|i| wm.insert(i, i).0).collect();
+    wm.gc();
+    marks.truncate(400);
+    assert!(!wm.needs_gc());
+}
+
+#[test]
+fn adaptive_gc_mostly_dead() {
+    // a target no sweep can meet must not stop sweeps altogether
+    let mut wm : HashMap<u32, u32> = HashMap::with_adaptive_gc(Duration::from_nanos(1));
+    let marks : Vec<MarkOnDrop> = (0..2000).map(|i| wm.insert(i, i).0).collect();
+    wm.gc();
+    drop(marks);
+    assert!(wm.needs_gc());
+    for i in 0..4000 {
+        drop(wm.insert(i, i));
+    }
+    assert!(wm.len() < 4000);
+}
+
+#[test]
+fn snapshot_pairs() {
+    let mut wm : HashMap<u32, String> = HashMap::new();
+    let (a, _)  = wm.insert(1, "a".to_string());
+    let (_b, _) = wm.insert(2, "b".to_string());
+    drop(a);
+
+    assert_eq!(wm.snapshot_values(), vec!["b".to_string()]);
+    assert_eq!(wm.len(), 1);
+    let pairs = wm.snapshot_pairs();
+    let pairs = std::thread::spawn(move || pairs).join().unwrap();
+    assert_eq!(pairs, vec![(2, "b".to_string())]);
+}
+
+#[test]
+fn marker_pool() {
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    let marks : Vec<MarkOnDrop> = (0..10).map(|i| wm.insert(i, i).0).collect();
+    let ids : Vec<u64> = marks.iter().map(|m| m.id()).collect();
+    // a downgraded mark still refers to its marker, which must not be reused
+    let weak = marks.into_iter().next().unwrap().downgrade();
+    wm.gc();
+    assert_eq!(wm.hooks.pool.len(), 9);
+
+    let (mark, _) = wm.insert(100, 100);
+    assert_eq!(wm.hooks.pool.len(), 8);
+    assert!(!ids.contains(&mark.id()));
+    assert_eq!(wm.key_for(mark.id()), Some(&100));
+    assert!(weak.upgrade().is_none());
+    drop(mark);
+    assert_eq!(wm.get(&100), None);
+}
+
+#[test]
+fn teardown() {
+    let (tx, rx) = mpsc::channel();
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    wm.set_teardown(move |k, v| tx.send((k, v)).unwrap());
+    let (a, _)  = wm.insert(1, 10);
+    let (_b, _) = wm.insert(2, 20);
+    drop(a);
+
+    drop(wm);
+    assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![(2, 20)]);
+}
+
+#[test]
+fn insert_with_policy() {
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    let (a, _) = wm.insert(1, 10);
+
+    let err = wm.insert_with_policy(1, 11, Replace::KeepExisting).err().unwrap();
+    assert_eq!((err.key, err.value), (1, 11));
+    assert!(wm.insert_with_policy(1, 11, Replace::IfDead).is_err());
+    assert!(wm.insert_with_policy(2, 20, Replace::IfDead).is_err());
+
+    // the dead value is handed back instead of being dropped
+    drop(a);
+    let (b, old) = wm.insert_with_policy(1, 12, Replace::IfDead).unwrap();
+    assert_eq!(old, Some(10));
+    let (_c, old) = wm.insert_with_policy(1, 13, Replace::Overwrite).unwrap();
+    assert_eq!(old, Some(12));
+    drop(b);
+    assert_eq!(wm.get(&1), Some(&13));
+    let (_d, old) = wm.insert_with_policy(2, 20, Replace::KeepExisting).unwrap();
+    assert_eq!(old, None);
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn clone_replace_keeps_original() {
+    let mut wm : HashMap<u32, u8> = HashMap::new();
+    let (_mark, _) = wm.insert(1, 7);
+
+    let mut other = wm.clone();
+    let (_other_mark, old) = other.insert(1, 11);
+    assert_eq!(old, Some(7));
+    assert_eq!(wm.get(&1), Some(&7));
+    assert_eq!(other.get(&1), Some(&11));
+
+    drop(other);
+    assert_eq!(wm.get(&1), Some(&7));
+    wm.assert_invariants();
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn clone_collect_keeps_counter() {
+    let mut wm : HashMap<u32, u8> = HashMap::new();
+    let (mark, _) = wm.insert(1, 7);
+    let mut other = wm.clone();
+
+    drop(mark);
+    other.gc();
+    other.assert_invariants();
+    wm.assert_invariants();
+
+    wm.gc();
+    assert!(wm.is_empty());
+    assert!(!wm.needs_gc());
+    wm.assert_invariants();
+
+    // the other way round, the clone lets go on drop
+    let (mark, _) = wm.insert(2, 8);
+    let other = wm.clone();
+    drop(mark);
+    wm.gc();
+    drop(other);
+    assert!(!wm.needs_gc());
+    wm.assert_invariants();
+}
+
+#[test]
+fn gc_full_prunes_adopted() {
+    let mut wm : HashMap<u32, u32> = HashMap::with_manual_gc();
+    let mut other = HashMap::new();
+    let (mark, _) = other.insert(1, 1);
+    wm.merge(other);
+    assert_eq!(wm.adopted.len(), 1);
+
+    drop(mark);
+    assert_eq!(wm.gc_full().collected, 1);
+    assert!(wm.adopted.is_empty());
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn pin_parks_dead() {
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    let pending = |wm: &HashMap<u32, u32>| wm.gc.load(Ordering::SeqCst);
+    let mut marks : Vec<MarkOnDrop> = (0..4).map(|i| wm.insert(i, i).0).collect();
+    for i in 0..4 {
+        assert!(wm.pin(&i));
+    }
+    let weak = marks.pop().unwrap().downgrade();
+    marks.clear();
+    assert!(wm.needs_gc());
+
+    // the sweep keeps them and stops counting them
+    assert_eq!(wm.gc().collected, 0);
+    assert_eq!(pending(&wm), 0);
+    assert!(!wm.needs_gc());
+    wm.assert_invariants();
+
+    assert!(wm.unpin(&0));
+    assert_eq!(pending(&wm), 1);
+    let (_one, _) = wm.insert(1, 10);
+    let _three = weak.upgrade().unwrap();
+    assert_eq!(pending(&wm), 1);
+    wm.assert_invariants();
+
+    assert_eq!(wm.gc().collected, 1);
+    assert_eq!(wm.len(), 3);
+    wm.assert_invariants();
 }
 
 #[test]
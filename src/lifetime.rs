@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+// bucket i holds lifetimes below 2^i microseconds, the last one everything else
+const BUCKETS: usize = 40;
+
+/// Histogram of how long entries were stored before they were collected,
+/// see `HashMap::lifetime_stats`.
+///
+/// Lifetimes are measured up to the collection, not to the moment the mark
+/// dropped, so they run long by however much sweeps lag behind. Buckets are
+/// powers of two, quantiles are accurate to within a factor of two.
+#[derive(Clone, Debug)]
+pub struct LifetimeStats {
+    buckets: [u64; BUCKETS],
+    count:   u64,
+    total:   Duration,
+    max:     Duration,
+}
+
+impl Default for LifetimeStats {
+    fn default() -> Self {
+        LifetimeStats {
+            buckets: [0; BUCKETS],
+            count:   0,
+            total:   Duration::ZERO,
+            max:     Duration::ZERO,
+        }
+    }
+}
+
+impl LifetimeStats {
+    pub(crate) fn record(&mut self, lifetime: Duration) {
+        let micros = lifetime.as_micros();
+        let bucket = (128 - micros.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += lifetime;
+        self.max = self.max.max(lifetime);
+    }
+
+    /// Number of collected entries that were recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        // dividing by a u32 would truncate counts beyond 4 billion
+        Some(Duration::from_nanos((self.total.as_nanos() / self.count as u128) as u64))
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        if self.count == 0 { None } else { Some(self.max) }
+    }
+
+    /// Upper bound of the lifetime that a fraction `q` of the recorded
+    /// entries did not exceed, e.g. 0.99 for p99.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                if i == BUCKETS - 1 {
+                    break;
+                }
+                return Some(Duration::from_micros(1 << i).min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    pub fn p50(&self) -> Option<Duration> {
+        self.quantile(0.5)
+    }
+
+    pub fn p99(&self) -> Option<Duration> {
+        self.quantile(0.99)
+    }
+}
+
+
+#[test]
+fn lifetime_mean_many() {
+    let stats = LifetimeStats {
+        count: 1 << 33,
+        total: Duration::from_secs(3 << 33),
+        ..LifetimeStats::default()
+    };
+    assert_eq!(stats.mean(), Some(Duration::from_secs(3)));
+}
@@ -0,0 +1,306 @@
+//! Single threaded variant of the map. Markers are `Rc<Cell<_>>` instead of
+//! atomics, so neither the map nor its marks can be sent to another thread.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use super::GcReport;
+
+// same states as the atomic markers
+const LIVE:      u8 = 0;
+const DEAD:      u8 = 1;
+const COLLECTED: u8 = 2;
+
+type Marker = Rc<Cell<u8>>;
+
+pub struct MarkOnDrop {
+    marker: Marker,
+    gc:     Rc<Cell<usize>>,
+}
+
+impl MarkOnDrop {
+    fn new(gc: &Rc<Cell<usize>>) -> Self {
+        MarkOnDrop {
+            marker: Rc::new(Cell::new(LIVE)),
+            gc:     gc.clone(),
+        }
+    }
+}
+
+impl Drop for MarkOnDrop {
+    fn drop(&mut self) {
+        // only entries the map still stores count towards `gc`
+        if self.marker.get() == LIVE {
+            self.marker.set(DEAD);
+            self.gc.set(self.gc.get() + 1);
+        }
+    }
+}
+
+fn is_dead(marker: &Marker) -> bool {
+    marker.get() != LIVE
+}
+
+// see `Marker::collect` and `Marker::detach` of the atomic markers
+fn collect(marker: &Marker, gc: &Cell<usize>) -> bool {
+    match marker.get() {
+        LIVE => false,
+        DEAD => {
+            marker.set(COLLECTED);
+            gc.set(gc.get().saturating_sub(1));
+            true
+        },
+        _ => true,
+    }
+}
+
+fn detach(marker: &Marker, gc: &Cell<usize>) -> bool {
+    let state = marker.replace(COLLECTED);
+    if state == DEAD {
+        gc.set(gc.get().saturating_sub(1));
+    }
+    state == LIVE
+}
+
+pub struct HashMap<K, V> {
+    v:  std::collections::HashMap<K, (V, Marker)>,
+    gc: Rc<Cell<usize>>,
+}
+
+impl<K,V> Default for HashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    fn default() -> Self {
+        HashMap {
+            v:  std::collections::HashMap::new(),
+            gc: Rc::new(Cell::new(0)),
+        }
+    }
+}
+
+impl<K, V> HashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K,V> HashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    pub fn insert(&mut self, k: K, v: V) -> (MarkOnDrop, Option<V>) {
+        self.maybe_gc();
+        let mark = MarkOnDrop::new(&self.gc);
+        let old = match self.v.insert(k, (v, mark.marker.clone())) {
+            None => None,
+            Some((v, marker)) => {
+                if detach(&marker, &self.gc) {
+                    Some(v)
+                } else {
+                    None
+                }
+            }
+        };
+        (mark, old)
+    }
+
+    pub fn get<Q>(&mut self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.remove_dead(k);
+        self.v.get(k).map(|(v,_)|v)
+    }
+
+    pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.remove_dead(k);
+        self.v.get_mut(k).map(|(v,_)|v)
+    }
+
+    fn remove_dead<Q>(&mut self, k: &Q)
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        if self.v.get(k).map(|(_, marker)| collect(marker, &self.gc)).unwrap_or(false) {
+            self.v.remove(k);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.v.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    fn maybe_gc(&mut self) {
+        if self.gc.get() > self.len() / 2 {
+            self.gc();
+        }
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        let start = std::time::Instant::now();
+        let scanned = self.len();
+        let gc = &self.gc;
+        self.v.retain(|_, (_, marker)| !collect(marker, gc));
+        GcReport {
+            scanned,
+            collected: scanned - self.len(),
+            elapsed:   start.elapsed(),
+        }
+    }
+
+    pub fn entry(&mut self, k: K) -> Entry<'_, K, V> {
+        self.maybe_gc();
+        self.remove_dead(&k);
+
+        match self.v.entry(k) {
+            std::collections::hash_map::Entry::Occupied(n) => {
+                Entry::Occupied(OccupiedEntry{n})
+            },
+            std::collections::hash_map::Entry::Vacant(n) => {
+                Entry::Vacant(VacantEntry{n, gc: self.gc.clone()})
+            },
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.v.iter().filter_map(|(k, (v,marker))|{
+            if is_dead(marker) {
+                None
+            } else {
+                Some((k,v))
+            }
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+        self.v.iter_mut().filter_map(|(k, (v,marker))|{
+            if is_dead(marker) {
+                None
+            } else {
+                Some((k,v))
+            }
+        })
+    }
+}
+
+
+pub struct OccupiedEntry<'a, K: 'a, V: 'a>{
+    n: std::collections::hash_map::OccupiedEntry<'a, K, (V,Marker)>,
+}
+
+pub struct VacantEntry<'a, K: 'a, V: 'a>{
+    n: std::collections::hash_map::VacantEntry<'a, K, (V,Marker)>,
+    gc: Rc<Cell<usize>>,
+}
+
+pub enum Entry<'a, K: 'a, V: 'a> {
+    /// An occupied entry.
+    Occupied(OccupiedEntry<'a, K, V>),
+
+    /// A vacant entry.
+    Vacant(VacantEntry<'a, K, V>),
+}
+
+impl<'a, K, V> OccupiedEntry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        self.n.key()
+    }
+
+    pub fn get(&self) -> &V {
+        &self.n.get().0
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.n.get_mut().0
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self.n.into_mut().0
+    }
+}
+
+impl<'a, K, V> VacantEntry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        self.n.key()
+    }
+
+    pub fn into_key(self) -> K {
+        self.n.into_key()
+    }
+
+    pub fn insert_with<F: FnOnce(MarkOnDrop) -> V>(self, value: F) -> &'a mut V {
+        let mark = MarkOnDrop::new(&self.gc);
+        let marker = mark.marker.clone();
+        &mut (self.n.insert((value(mark), marker)).0)
+    }
+}
+
+impl<'a, K, V> Entry<'a, K, V> {
+    pub fn or_insert_with<F: FnOnce(MarkOnDrop) -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry)  => entry.into_mut(),
+            Entry::Vacant(entry)    => {
+                entry.insert_with(default)
+            }
+        }
+    }
+}
+
+
+#[test]
+fn local_insert() {
+    let mut wm : HashMap<u32, &'static str> = HashMap::new();
+    let (mark, _) = wm.insert(1, "hello");
+    let (_mark, old) = wm.insert(2, "world");
+    assert_eq!(old, None);
+    assert_eq!(wm.get(&1), Some(&"hello"));
+
+    drop(mark);
+    assert_eq!(wm.get(&1), None);
+    assert_eq!(wm.iter().collect::<Vec<_>>(), vec![(&2, &"world")]);
+
+    drop(_mark);
+    assert_eq!(wm.len(), 1);
+    assert!(wm.is_empty());
+}
+
+#[test]
+fn local_entry() {
+    let mut wm : HashMap<u32, u8> = HashMap::new();
+    let mut holdme = None;
+
+    *wm.entry(1).or_insert_with(|mark|{
+        holdme = Some(mark);
+        2
+    }) += 1;
+    assert_eq!(wm.get(&1), Some(&3));
+
+    drop(holdme);
+    assert_eq!(wm.get(&1), None);
+}
+
+#[test]
+fn local_dead_count() {
+    let mut wm : HashMap<u32, u32> = HashMap::new();
+    let (a, _) = wm.insert(1, 1);
+    let (b, _) = wm.insert(2, 2);
+
+    // replaced and collected entries no longer count
+    let (_a, _) = wm.insert(1, 10);
+    drop(a);
+    assert_eq!(wm.gc.get(), 0);
+    drop(b);
+    assert_eq!(wm.gc.get(), 1);
+    assert_eq!(wm.get(&2), None);
+    assert_eq!(wm.gc.get(), 0);
+    assert_eq!(wm.gc().collected, 0);
+    assert_eq!(wm.get(&1), Some(&10));
+}
@@ -0,0 +1,241 @@
+use super::storage::GcMap;
+use super::GcReport;
+use super::MarkOnDrop;
+
+struct Item<V> {
+    value:  V,
+    tick:   u64,
+    weight: usize,
+}
+
+/// Entries in recency order with their total weight, the part shared by
+/// `LruHashMap` and `WeightedHashMap`. What counts as over the limit is up
+/// to them, an `LruHashMap` weighs every entry as one.
+pub(crate) struct Lru<K, V> {
+    v:      GcMap<K, Item<V>>,
+    order:  std::collections::BTreeMap<u64, K>,
+    tick:   u64,
+    weight: usize,
+}
+
+impl<K, V> Lru<K, V>
+    where K: std::cmp::Eq + std::hash::Hash + Clone
+{
+    pub(crate) fn new() -> Self {
+        Lru {
+            v:      GcMap::new(),
+            order:  std::collections::BTreeMap::new(),
+            tick:   0,
+            weight: 0,
+        }
+    }
+
+    /// Total weight of all stored entries, including dead ones.
+    pub(crate) fn weight(&self) -> usize {
+        self.weight
+    }
+
+    // drop the bookkeeping of an entry that left the map
+    fn forget(order: &mut std::collections::BTreeMap<u64, K>, weight: &mut usize, item: &Item<V>) {
+        order.remove(&item.tick);
+        *weight -= item.weight;
+    }
+
+    pub(crate) fn insert(&mut self, k: K, v: V, weight: usize) -> (MarkOnDrop, Option<V>) {
+        self.maybe_gc();
+        if let Some(item) = self.v.remove_dead(&k) {
+            Self::forget(&mut self.order, &mut self.weight, &item);
+        }
+        self.tick += 1;
+        self.weight += weight;
+        self.order.insert(self.tick, k.clone());
+
+        let (mark, old) = self.v.replace(k, Item{value: v, tick: self.tick, weight});
+        let old = match old {
+            None => None,
+            Some((item, live)) => {
+                Self::forget(&mut self.order, &mut self.weight, &item);
+                if live {
+                    Some(item.value)
+                } else {
+                    None
+                }
+            }
+        };
+        (mark, old)
+    }
+
+    /// Collect dead entries, then evict from the least recently used end
+    /// until the weight is within `budget`. `f` gets the live evicted ones.
+    pub(crate) fn shrink_to<F>(&mut self, budget: usize, mut f: F)
+        where F: FnMut(K, V),
+    {
+        if self.weight > budget && self.v.pending() > 0 {
+            self.gc();
+        }
+        while self.weight > budget && self.evict(&mut f) {}
+    }
+
+    fn evict<F>(&mut self, f: &mut F) -> bool
+        where F: FnMut(K, V),
+    {
+        let tick = match self.order.keys().next() {
+            Some(&tick) => tick,
+            None => return false,
+        };
+        let k = self.order.remove(&tick).unwrap();
+        // invalidates the outstanding mark
+        if let Some((item, live)) = self.v.take(&k) {
+            self.weight -= item.weight;
+            if live {
+                f(k, item.value);
+            }
+        }
+        true
+    }
+
+    /// Marks `k` as most recently used. Dead entries are removed instead.
+    pub(crate) fn touch<Q>(&mut self, k: &Q) -> Option<&mut V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        if let Some(item) = self.v.remove_dead(k) {
+            Self::forget(&mut self.order, &mut self.weight, &item);
+            return None;
+        }
+        let item = self.v.get_live_mut(k)?;
+        self.tick += 1;
+        let key = self.order.remove(&item.tick).unwrap();
+        self.order.insert(self.tick, key);
+        item.tick = self.tick;
+        Some(&mut item.value)
+    }
+
+    pub(crate) fn peek<Q>(&self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.v.get_live(k).map(|item| &item.value)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.v.len()
+    }
+
+    fn maybe_gc(&mut self) {
+        if self.v.pending() > self.len() / 2 {
+            self.gc();
+        }
+    }
+
+    pub(crate) fn gc(&mut self) -> GcReport {
+        let order = &mut self.order;
+        let weight = &mut self.weight;
+        self.v.gc_with(|_, item| Self::forget(order, weight, item))
+    }
+
+    /// Live entries from least to most recently used.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.order.values().filter_map(move |k|{
+            self.peek(k).map(|v| (k, v))
+        })
+    }
+}
+
+/// Variant bounded to `max_len` entries. When an insert goes over the limit
+/// dead entries are collected first, then the least recently used live entry
+/// is evicted and its outstanding mark no longer keeps anything alive.
+pub struct LruHashMap<K, V> {
+    lru:     Lru<K, V>,
+    max_len: usize,
+}
+
+impl<K, V> LruHashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash + Clone
+{
+    pub fn new(max_len: usize) -> Self {
+        LruHashMap {
+            lru:     Lru::new(),
+            max_len: max_len.max(1),
+        }
+    }
+}
+
+impl<K,V> LruHashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash + Clone
+{
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    pub fn insert(&mut self, k: K, v: V) -> (MarkOnDrop, Option<V>) {
+        let inserted = self.lru.insert(k, v, 1);
+        self.lru.shrink_to(self.max_len, |_, _| ());
+        inserted
+    }
+
+    pub fn get<Q>(&mut self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.lru.touch(k).map(|v| &*v)
+    }
+
+    pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.lru.touch(k)
+    }
+
+    /// Lookup without updating recency.
+    pub fn peek<Q>(&self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.lru.peek(k)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lru.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        self.lru.gc()
+    }
+
+    /// Live entries from least to most recently used.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.lru.iter()
+    }
+}
+
+
+#[test]
+fn lru_evicts_oldest() {
+    let mut wm : LruHashMap<u32, u32> = LruHashMap::new(2);
+    let (_a, _) = wm.insert(1, 10);
+    let (_b, _) = wm.insert(2, 20);
+    assert_eq!(wm.get(&1), Some(&10));
+
+    let (_c, _) = wm.insert(3, 30);
+    assert_eq!(wm.len(), 2);
+    assert_eq!(wm.peek(&2), None);
+    assert_eq!(wm.iter().collect::<Vec<_>>(), vec![(&1, &10), (&3, &30)]);
+}
+
+#[test]
+fn lru_prefers_dead() {
+    let mut wm : LruHashMap<u32, u32> = LruHashMap::new(2);
+    let (_a, _) = wm.insert(1, 10);
+    let (b, _)  = wm.insert(2, 20);
+    drop(b);
+
+    let (_c, _) = wm.insert(3, 30);
+    assert_eq!(wm.get(&1), Some(&10));
+    assert_eq!(wm.get(&3), Some(&30));
+}
@@ -0,0 +1,285 @@
+use super::atomic::AtomicUsize;
+use super::atomic::Ordering;
+use super::atomic::Arc;
+
+// the low bits of the state word, the rest counts the maps holding the entry
+const LIVE:      usize = 0;
+const DEAD:      usize = 1;
+const COLLECTED: usize = 2;
+// dead, but taken out of the gc counter while a map pins the entry
+const PARKED:    usize = 3;
+const STATE:     usize = 3;
+const HOLDER:    usize = 4;
+
+/// Liveness of one entry, shared between the map and the entry's mark.
+///
+/// A live entry turns dead when its mark drops, and collected once the map
+/// let go of it. Only a dead entry that was not collected yet can be revived.
+/// A dead entry that is pinned gets parked, so it no longer counts towards
+/// `gc` until it is unpinned.
+///
+/// Cloned maps store the same marker. Each of them holds it, and only the
+/// last one letting go collects it, so the others keep their entry.
+pub(crate) struct Marker {
+    state:         AtomicUsize,
+    pub(crate) id: u64,
+}
+
+impl Marker {
+    pub(crate) fn new() -> Self {
+        Marker {
+            state: AtomicUsize::new(HOLDER | LIVE),
+            id:    super::atomic::next_id(),
+        }
+    }
+
+    pub(crate) fn is_dead(&self) -> bool {
+        self.state.load(Ordering::SeqCst) & STATE != LIVE
+    }
+
+    pub(crate) fn is_collected(&self) -> bool {
+        self.state.load(Ordering::SeqCst) & STATE == COLLECTED
+    }
+
+    pub(crate) fn is_parked(&self) -> bool {
+        self.state.load(Ordering::SeqCst) & STATE == PARKED
+    }
+
+    pub(crate) fn kill(&self) -> bool {
+        self.turn(LIVE, DEAD)
+    }
+
+    /// Returns whether the entry was counted towards `gc`, or None if it
+    /// could not be revived.
+    fn revive(&self) -> Option<bool> {
+        if self.turn(DEAD, LIVE) {
+            Some(true)
+        } else if self.turn(PARKED, LIVE) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Called by a map that pins the dead entry, which sweeps would
+    /// otherwise keep counting.
+    pub(crate) fn park(&self, gc: &AtomicUsize) {
+        if self.turn(DEAD, PARKED) {
+            uncount(gc);
+        }
+    }
+
+    /// Called when the map unpins the entry, or lets go of it while pinned.
+    pub(crate) fn unpark(&self, gc: &AtomicUsize) {
+        if self.turn(PARKED, DEAD) {
+            gc.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn turn(&self, from: usize, to: usize) -> bool {
+        self.state.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |s| {
+            if s & STATE == from { Some(s & !STATE | to) } else { None }
+        }).is_ok()
+    }
+
+    /// Called by a map that stores the entry as well, e.g. a clone.
+    pub(crate) fn share(&self) {
+        self.state.fetch_add(HOLDER, Ordering::SeqCst);
+    }
+
+    /// Called by the map before removing a dead entry. Returns false if the
+    /// entry is live, which includes having been revived since it was seen dead.
+    ///
+    /// The map's `gc` counter is decremented for the entry that was counted
+    /// when its mark dropped, so the counter only ever covers dead entries
+    /// that are still stored.
+    pub(crate) fn collect(&self, gc: &AtomicUsize) -> bool {
+        let prev = self.state.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |s| match s & STATE {
+            DEAD | PARKED if s >= 2 * HOLDER => Some(s - HOLDER),
+            DEAD | PARKED => Some(s & !STATE | COLLECTED),
+            _ => None,
+        });
+        match prev {
+            Ok(s) if s < 2 * HOLDER => {
+                if s & STATE == DEAD {
+                    uncount(gc);
+                }
+                true
+            },
+            Ok(_) => true,
+            Err(s) => s & STATE == COLLECTED,
+        }
+    }
+
+    /// Called by the map when it removes an entry regardless of its mark,
+    /// e.g. on replacement or eviction. Returns true if the entry was live.
+    /// A mark dropping later no longer counts towards `gc`, unless another
+    /// map still holds the entry.
+    pub(crate) fn detach(&self, gc: &AtomicUsize) -> bool {
+        let prev = self.state.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |s| match s & STATE {
+            COLLECTED => None,
+            _ if s >= 2 * HOLDER => Some(s - HOLDER),
+            _ => Some(s & !STATE | COLLECTED),
+        });
+        match prev {
+            Ok(s) if s & STATE == LIVE => true,
+            Ok(s) if s < 2 * HOLDER && s & STATE == DEAD => {
+                uncount(gc);
+                false
+            },
+            _ => false,
+        }
+    }
+}
+
+// markers kept per map, beyond this collected ones are freed
+const POOL_SIZE: usize = 1024;
+
+/// Markers of collected entries, handed out again to new inserts instead
+/// of allocating. A marker is only kept once nothing else refers to it.
+#[derive(Default)]
+pub(crate) struct MarkerPool {
+    free: Vec<Arc<Marker>>,
+}
+
+impl MarkerPool {
+    pub(crate) fn mark(&mut self, gc: &Arc<AtomicUsize>) -> MarkOnDrop {
+        match self.free.pop() {
+            Some(marker) => MarkOnDrop{marker, gc: gc.clone()},
+            None => MarkOnDrop::new(gc),
+        }
+    }
+
+    // loom's Arc has no get_mut, so nothing is recycled there
+    #[cfg(not(loom))]
+    pub(crate) fn put(&mut self, mut marker: Arc<Marker>) {
+        if self.free.len() >= POOL_SIZE {
+            return;
+        }
+        if let Some(unique) = Arc::get_mut(&mut marker) {
+            // a fresh id, the old one must not find the new entry
+            *unique = Marker::new();
+        } else {
+            return;
+        }
+        self.free.push(marker);
+    }
+
+    #[cfg(loom)]
+    pub(crate) fn put(&mut self, _marker: Arc<Marker>) {}
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.free.len()
+    }
+}
+
+pub(crate) fn uncount(gc: &AtomicUsize) {
+    let _ = gc.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| Some(n.saturating_sub(1)));
+}
+
+pub struct MarkOnDrop {
+    pub(crate) marker: Arc<Marker>,
+    pub(crate) gc:     Arc<AtomicUsize>,
+}
+
+impl MarkOnDrop {
+    pub(crate) fn new(gc: &Arc<AtomicUsize>) -> Self {
+        MarkOnDrop {
+            marker: Arc::new(Marker::new()),
+            gc:     gc.clone(),
+        }
+    }
+
+    /// Unique for the lifetime of the process, see `HashMap::key_for`.
+    pub fn id(&self) -> u64 {
+        self.marker.id
+    }
+
+    /// Turn the mark into a pointer that can travel through foreign code,
+    /// e.g. as the context of a C callback. The entry stays alive until
+    /// `from_raw` is called and the result dropped.
+    pub fn into_raw(self) -> *mut () {
+        Box::into_raw(Box::new(self)) as *mut ()
+    }
+
+    /// Take back a mark passed out by `into_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw` and must not be used again afterwards.
+    pub unsafe fn from_raw(ptr: *mut ()) -> Self {
+        *Box::from_raw(ptr as *mut MarkOnDrop)
+    }
+
+    /// Release the entry like dropping the mark would, but keep a handle
+    /// that can re-arm it as long as the map has not collected it yet.
+    pub fn downgrade(self) -> WeakMark {
+        WeakMark {
+            marker: self.marker.clone(),
+            gc:     self.gc.clone(),
+        }
+    }
+}
+
+impl Drop for MarkOnDrop {
+    fn drop(&mut self) {
+        // count before the entry turns dead, otherwise the map could collect
+        // it and uncount in between, leaving the counter one too high.
+        self.gc.fetch_add(1, Ordering::SeqCst);
+        if !self.marker.kill() {
+            uncount(&self.gc);
+        }
+    }
+}
+
+/// A guard that keeps a map entry alive by owning its mark. Implement this
+/// for a struct that holds a `MarkOnDrop` next to whatever else should live
+/// exactly as long as the entry, e.g. a socket or a permit. Dropping the
+/// struct drops the mark, no `Drop` impl is needed for that.
+pub trait GcHandle {
+    fn mark(&self) -> &MarkOnDrop;
+
+    /// Same as the id of the owned mark.
+    fn id(&self) -> u64 {
+        self.mark().id()
+    }
+}
+
+impl GcHandle for MarkOnDrop {
+    fn mark(&self) -> &MarkOnDrop {
+        self
+    }
+}
+
+impl<H: GcHandle + ?Sized> GcHandle for Box<H> {
+    fn mark(&self) -> &MarkOnDrop {
+        (**self).mark()
+    }
+}
+
+/// A released mark that can be upgraded again until its entry is collected.
+#[derive(Clone)]
+pub struct WeakMark {
+    marker: Arc<Marker>,
+    gc:     Arc<AtomicUsize>,
+}
+
+impl WeakMark {
+    /// Same as the id of the mark this was downgraded from.
+    pub fn id(&self) -> u64 {
+        self.marker.id
+    }
+
+    /// Revive the entry and return a new mark for it. Fails if the map
+    /// already collected the entry, or another handle revived it first.
+    pub fn upgrade(&self) -> Option<MarkOnDrop> {
+        if self.marker.revive()? {
+            uncount(&self.gc);
+        }
+        Some(MarkOnDrop {
+            marker: self.marker.clone(),
+            gc:     self.gc.clone(),
+        })
+    }
+}
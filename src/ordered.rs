@@ -0,0 +1,271 @@
+use super::storage::GcLookup;
+use super::storage::GcMap;
+use super::storage::GcStorage;
+use super::storage::Stored;
+use super::GcReport;
+use super::MarkOnDrop;
+
+/// Entries in insertion order, with an index from key to position.
+///
+/// Removed entries leave a hole so the positions of the others stay valid.
+/// The holes are compacted away once they make up half of the entries.
+struct InsertionOrder<K, E> {
+    entries: Vec<Option<(K, E)>>,
+    index:   std::collections::HashMap<K, usize>,
+    holes:   usize,
+}
+
+impl<K, E> Default for InsertionOrder<K, E> {
+    fn default() -> Self {
+        InsertionOrder {
+            entries: Vec::new(),
+            index:   std::collections::HashMap::new(),
+            holes:   0,
+        }
+    }
+}
+
+impl<K, E> InsertionOrder<K, E>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    fn maybe_compact(&mut self) {
+        if self.holes <= self.entries.len() / 2 {
+            return;
+        }
+        self.entries.retain(Option::is_some);
+        self.holes = 0;
+        for (i, (k, _)) in self.entries.iter().flatten().enumerate() {
+            if let Some(j) = self.index.get_mut(k) {
+                *j = i;
+            }
+        }
+    }
+}
+
+impl<K, Q, E> GcLookup<Q, E> for InsertionOrder<K, E>
+    where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+          K: std::cmp::Eq + std::hash::Hash + std::borrow::Borrow<Q>,
+{
+    fn get(&self, k: &Q) -> Option<&E> {
+        let i = *self.index.get(k)?;
+        self.entries[i].as_ref().map(|(_, e)| e)
+    }
+
+    fn get_mut(&mut self, k: &Q) -> Option<&mut E> {
+        let i = *self.index.get(k)?;
+        self.entries[i].as_mut().map(|(_, e)| e)
+    }
+
+    fn remove(&mut self, k: &Q) -> Option<E> {
+        let i = self.index.remove(k)?;
+        let (_, e) = self.entries[i].take()?;
+        self.holes += 1;
+        self.maybe_compact();
+        Some(e)
+    }
+}
+
+impl<K, E> GcStorage<K, E> for InsertionOrder<K, E>
+    where K: std::cmp::Eq + std::hash::Hash + Clone
+{
+    fn insert(&mut self, k: K, e: E) -> Option<E> {
+        if let Some(&i) = self.index.get(&k) {
+            if let Some((_, ref mut old)) = self.entries[i] {
+                return Some(std::mem::replace(old, e));
+            }
+        }
+        self.index.insert(k.clone(), self.entries.len());
+        self.entries.push(Some((k, e)));
+        None
+    }
+
+    fn retain<F: FnMut(&K, &mut E) -> bool>(&mut self, mut f: F) {
+        for slot in self.entries.iter_mut() {
+            let keep = match *slot {
+                Some((ref k, ref mut e)) => f(k, e),
+                None => true,
+            };
+            if !keep {
+                if let Some((k, _)) = slot.take() {
+                    self.index.remove(&k);
+                    self.holes += 1;
+                }
+            }
+        }
+        self.maybe_compact();
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len() - self.holes
+    }
+
+    fn for_each<F: FnMut(&K, &E)>(&self, mut f: F) {
+        self.entries.iter().flatten().for_each(|(k, e)| f(k, e))
+    }
+}
+
+/// Variant that iterates in insertion order and supports index based access.
+///
+/// Dead entries stay in place as tombstones until the next sweep, which keeps
+/// the remaining entries in order. Replacing a live entry keeps its position,
+/// inserting over a dead one appends at the end.
+pub struct OrderedHashMap<K, V> {
+    v: GcMap<K, V, InsertionOrder<K, Stored<V>>>,
+}
+
+impl<K,V> Default for OrderedHashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash + Clone
+{
+    fn default() -> Self {
+        OrderedHashMap {
+            v: GcMap::new(),
+        }
+    }
+}
+
+impl<K, V> OrderedHashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash + Clone
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K,V> OrderedHashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash + Clone
+{
+    pub fn insert(&mut self, k: K, v: V) -> (MarkOnDrop, Option<V>) {
+        self.v.insert(k, v)
+    }
+
+    pub fn get<Q>(&self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.v.get_live(k)
+    }
+
+    pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.v.get_live_mut(k)
+    }
+
+    /// Position in the storage of the `i`th live entry. A plain index while
+    /// nothing is dead or removed, otherwise the entries up to it are walked.
+    fn position(&self, i: usize) -> Option<usize> {
+        let entries = &self.v.storage.entries;
+        if self.v.pending() == 0 && self.v.storage.holes == 0 {
+            return if i < entries.len() { Some(i) } else { None };
+        }
+        entries.iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.as_ref().map(|(_, e)| !e.marker.is_dead()).unwrap_or(false))
+            .nth(i)
+            .map(|(j, _)| j)
+    }
+
+    /// The `i`th live entry in insertion order.
+    pub fn get_index(&self, i: usize) -> Option<(&K, &V)> {
+        let i = self.position(i)?;
+        self.v.storage.entries[i].as_ref().map(|(k, e)| (k, &e.value))
+    }
+
+    pub fn get_index_mut(&mut self, i: usize) -> Option<(&K, &mut V)> {
+        let i = self.position(i)?;
+        self.v.storage.entries[i].as_mut().map(|(k, e)| (&*k, &mut e.value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.v.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        self.v.gc()
+    }
+
+    /// Live entries in insertion order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&K, &V)> {
+        self.v.storage.entries.iter().flatten().filter_map(|(k, e)|{
+            if e.marker.is_dead() {
+                None
+            } else {
+                Some((k, &e.value))
+            }
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl DoubleEndedIterator<Item = (&K, &mut V)> {
+        self.v.storage.entries.iter_mut().flatten().filter_map(|(k, e)|{
+            if e.marker.is_dead() {
+                None
+            } else {
+                Some((&*k, &mut e.value))
+            }
+        })
+    }
+}
+
+
+#[test]
+fn ordered_insertion_order() {
+    let mut wm : OrderedHashMap<&'static str, u32> = OrderedHashMap::new();
+    let (_c, _) = wm.insert("c", 1);
+    let (a, _)  = wm.insert("a", 2);
+    let (_b, _) = wm.insert("b", 3);
+    let (_c, old) = wm.insert("c", 4);
+    assert_eq!(old, Some(1));
+
+    drop(a);
+    assert_eq!(wm.iter().collect::<Vec<_>>(), vec![(&"c", &4), (&"b", &3)]);
+    assert_eq!(wm.get_index(1), Some((&"b", &3)));
+    assert_eq!(wm.get_index(2), None);
+
+    let (_a, _) = wm.insert("a", 5);
+    assert_eq!(wm.get_index(2), Some((&"a", &5)));
+    assert_eq!(wm.get("a"), Some(&5));
+}
+
+#[test]
+fn ordered_insert_over_tombstone() {
+    let mut wm : OrderedHashMap<&'static str, u32> = OrderedHashMap::new();
+    let (a, _)  = wm.insert("a", 1);
+    let (_b, _) = wm.insert("b", 2);
+
+    let weak = a.downgrade();
+    let (_a, old) = wm.insert("a", 3);
+    assert_eq!(old, None);
+    assert!(weak.upgrade().is_none());
+
+    assert_eq!(wm.len(), 2);
+    assert_eq!(wm.iter().collect::<Vec<_>>(), vec![(&"b", &2), (&"a", &3)]);
+    assert_eq!(wm.get("b"), Some(&2));
+    assert_eq!(wm.get_index(1), Some((&"a", &3)));
+}
+
+#[test]
+fn ordered_index_skips_holes() {
+    let mut wm : OrderedHashMap<u32, u32> = OrderedHashMap::new();
+    let mut marks : Vec<_> = (0..6).map(|i| wm.insert(i, i * 10).0).collect();
+
+    // a dropped mark and a re-insert over it, which leaves a hole
+    drop(marks.remove(1));
+    assert_eq!(wm.get_index(1), Some((&2, &20)));
+    let (_one, _) = wm.insert(1, 11);
+    assert_eq!(wm.len(), 6);
+    assert_eq!(wm.get_index(5), Some((&1, &11)));
+
+    // sweeping past half of them compacts the holes
+    marks.truncate(1);
+    assert_eq!(wm.gc().collected, 4);
+    assert_eq!(wm.v.storage.entries.len(), 2);
+    assert_eq!(wm.iter().collect::<Vec<_>>(), vec![(&0, &0), (&1, &11)]);
+    assert_eq!(wm.get_index(1), Some((&1, &11)));
+    *wm.get_index_mut(0).unwrap().1 += 1;
+    assert_eq!(wm.get(&0), Some(&1));
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+use std::time::Instant;
+
+use super::GcReport;
+
+// weight of the newest sweep in the smoothed cost and rate
+const SMOOTHING: f64 = 0.3;
+// share of the time between sweeps that may be spent sweeping
+const MAX_OVERHEAD: f64 = 0.1;
+// never wait for fewer dead entries than this share of the map
+const MIN_FRACTION: f64 = 1.0 / 16.0;
+// nor for more, so a map that is mostly dead always sweeps
+const MAX_FRACTION: f64 = 0.9;
+
+/// Decides when a map created `with_adaptive_gc` sweeps.
+///
+/// A sweep scans the whole map, so its pause grows with the map no matter
+/// when it runs. What adapts is how many dead entries are let pile up: a
+/// map whose sweeps are well below the target pause sweeps early and stays
+/// small, one whose sweeps reach the target waits for more dead entries, up
+/// to nine in ten stored entries, so long pauses come less often. Dead
+/// entries piling up fast also delay the next sweep, until ten times its
+/// pause has passed, so sweeping takes at most a tenth of the time between
+/// sweeps.
+#[derive(Clone, Debug)]
+pub(crate) struct Pacer {
+    target:   Duration,
+    // nanoseconds spent per scanned entry
+    cost:     Option<f64>,
+    // dead entries per second
+    rate:     Option<f64>,
+    last:     Instant,
+    fraction: f64,
+    // dead entries expected within `wait` of the last sweep, only waited
+    // for until then
+    min_dead: usize,
+    wait:     Duration,
+}
+
+impl Pacer {
+    pub(crate) fn new(target: Duration) -> Self {
+        Pacer {
+            target,
+            cost:     None,
+            rate:     None,
+            last:     Instant::now(),
+            fraction: 0.5,
+            min_dead: 0,
+            wait:     Duration::ZERO,
+        }
+    }
+
+    /// Dead entries at which a map holding `len` entries should sweep.
+    pub(crate) fn threshold(&self, len: usize) -> usize {
+        let mut threshold = (len as f64 * self.fraction) as usize;
+        if self.last.elapsed() < self.wait {
+            threshold = threshold.max(self.min_dead);
+        }
+        threshold.min((len as f64 * MAX_FRACTION) as usize)
+    }
+
+    /// Learn from a sweep that left `len` entries behind.
+    pub(crate) fn swept(&mut self, report: &GcReport, len: usize) {
+        let now = Instant::now();
+        let interval = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+
+        if report.scanned > 0 {
+            let cost = report.elapsed.as_nanos() as f64 / report.scanned as f64;
+            self.cost = Some(smooth(self.cost, cost));
+        }
+        if interval > 0.0 {
+            self.rate = Some(smooth(self.rate, report.collected as f64 / interval));
+        }
+
+        let pause = self.cost.unwrap_or(0.0) * len as f64;
+        let target = self.target.as_nanos().max(1) as f64;
+        self.fraction = (0.5 * pause / target).clamp(MIN_FRACTION, MAX_FRACTION);
+        self.wait = Duration::from_nanos((pause / MAX_OVERHEAD) as u64);
+        self.min_dead = (self.rate.unwrap_or(0.0) * self.wait.as_secs_f64()) as usize;
+    }
+}
+
+fn smooth(old: Option<f64>, new: f64) -> f64 {
+    match old {
+        Some(old) => old + SMOOTHING * (new - old),
+        None => new,
+    }
+}
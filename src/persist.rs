@@ -0,0 +1,235 @@
+//! Saving a map's live entries to a byte stream and restoring them, e.g.
+//! across a warm restart.
+//!
+//! The format is a magic number and `FORMAT_VERSION`, followed by the entry
+//! count and every key and value as written by their `Persist` impls. All
+//! integers are little endian.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use super::atomic::Arc;
+use super::mark::Marker;
+use super::HashMap;
+use super::MarkOnDrop;
+
+const MAGIC: [u8; 4] = *b"GCMP";
+
+/// Version written by `HashMap::save`. `restore` rejects any other.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Types that can be written by `HashMap::save` and read back by `restore`.
+pub trait Persist: Sized {
+    fn write_to(&self, w: &mut dyn Write) -> io::Result<()>;
+    fn read_from(r: &mut dyn Read) -> io::Result<Self>;
+}
+
+macro_rules! persist_int {
+    ($($t:ty),*) => {$(
+        impl Persist for $t {
+            fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+                w.write_all(&self.to_le_bytes())
+            }
+
+            fn read_from(r: &mut dyn Read) -> io::Result<Self> {
+                let mut b = [0; std::mem::size_of::<$t>()];
+                r.read_exact(&mut b)?;
+                Ok(<$t>::from_le_bytes(b))
+            }
+        }
+    )*}
+}
+
+persist_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl Persist for bool {
+    fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        (*self as u8).write_to(w)
+    }
+
+    fn read_from(r: &mut dyn Read) -> io::Result<Self> {
+        match u8::read_from(r)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("bool out of range")),
+        }
+    }
+}
+
+impl Persist for Vec<u8> {
+    fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        (self.len() as u64).write_to(w)?;
+        w.write_all(self)
+    }
+
+    fn read_from(r: &mut dyn Read) -> io::Result<Self> {
+        let len = u64::read_from(r)?;
+        // the length is untrusted, so grow with what is actually there
+        let mut v = Vec::new();
+        r.take(len).read_to_end(&mut v)?;
+        if v.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(v)
+    }
+}
+
+impl Persist for String {
+    fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        (self.len() as u64).write_to(w)?;
+        w.write_all(self.as_bytes())
+    }
+
+    fn read_from(r: &mut dyn Read) -> io::Result<Self> {
+        String::from_utf8(Vec::read_from(r)?).map_err(|_| invalid("string is not utf-8"))
+    }
+}
+
+impl<A: Persist, B: Persist> Persist for (A, B) {
+    fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.0.write_to(w)?;
+        self.1.write_to(w)
+    }
+
+    fn read_from(r: &mut dyn Read) -> io::Result<Self> {
+        Ok((A::read_from(r)?, B::read_from(r)?))
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+impl<K, V> HashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash + Persist,
+          V: Persist,
+{
+    /// Write the live entries to `w`, see the `persist` module for the format.
+    pub fn save<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let live : Vec<(&K, &V)> = self.iter().collect();
+        w.write_all(&MAGIC)?;
+        FORMAT_VERSION.write_to(&mut w)?;
+        (live.len() as u64).write_to(&mut w)?;
+        for (k, v) in live {
+            k.write_to(&mut w)?;
+            v.write_to(&mut w)?;
+        }
+        w.flush()
+    }
+
+    fn read_entries<R: Read>(mut r: R) -> io::Result<Vec<(K, V)>> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid("not a saved gcmap"));
+        }
+        if u32::read_from(&mut r)? != FORMAT_VERSION {
+            return Err(invalid("unsupported format version"));
+        }
+        let len = u64::read_from(&mut r)?;
+        let mut entries = Vec::new();
+        for _ in 0..len {
+            let k = K::read_from(&mut r)?;
+            let v = V::read_from(&mut r)?;
+            entries.push((k, v));
+        }
+        Ok(entries)
+    }
+
+    /// Rebuild a map written by `save`, handing the fresh mark of every
+    /// entry to `sink` like `from_std` does.
+    pub fn restore<R, F>(r: R, mut sink: F) -> io::Result<Self>
+        where R: Read,
+              F: FnMut(&K, MarkOnDrop),
+    {
+        let mut map = Self::new();
+        for (k, v) in Self::read_entries(r)? {
+            let mark = map.hooks.pool.mark(&map.gc);
+            let slot = super::Slot::new(v, mark.marker.clone(), map.config);
+            sink(&k, mark);
+            // a duplicate key replaces the earlier entry like insert does
+            if let Some(old) = map.v.insert(k, slot) {
+                old.detach(&map.gc);
+            }
+        }
+        Ok(map)
+    }
+
+    /// Rebuild a map written by `save` with every entry pinned instead of
+    /// marked. Entries stay until they are replaced, or die once unpinned.
+    pub fn restore_pinned<R: Read>(r: R) -> io::Result<Self> {
+        let mut map = Self::new();
+        for (k, v) in Self::read_entries(r)? {
+            // a live marker without a mark, so the gc counter stays at zero
+            let mut slot = super::Slot::new(v, Arc::new(Marker::new()), map.config);
+            slot.pinned = true;
+            if let Some(old) = map.v.insert(k, slot) {
+                old.detach(&map.gc);
+            }
+        }
+        Ok(map)
+    }
+}
+
+
+#[test]
+fn save_restore() {
+    let mut wm : HashMap<String, (u32, bool)> = HashMap::new();
+    let (a, _)  = wm.insert("a".to_string(), (1, true));
+    let (_b, _) = wm.insert("b".to_string(), (2, false));
+    drop(a);
+
+    let mut saved = Vec::new();
+    wm.save(&mut saved).unwrap();
+
+    let mut marks = Vec::new();
+    let mut restored : HashMap<String, (u32, bool)> = HashMap::restore(&saved[..], |k: &String, mark| marks.push((k.clone(), mark))).unwrap();
+    assert_eq!(restored.len(), 1);
+    assert_eq!(restored.get("b"), Some(&(2, false)));
+    marks.clear();
+    assert_eq!(restored.get("b"), None);
+
+    let mut pinned : HashMap<String, (u32, bool)> = HashMap::restore_pinned(&saved[..]).unwrap();
+    assert_eq!(pinned.get("b"), Some(&(2, false)));
+    assert!(!pinned.needs_gc());
+    pinned.gc();
+    assert_eq!(pinned.len(), 1);
+    assert!(pinned.unpin("b"));
+    assert_eq!(pinned.get("b"), None);
+
+    saved[4] = 9;
+    assert_eq!(HashMap::<String, (u32, bool)>::restore_pinned(&saved[..]).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn restore_duplicate_key() {
+    let saved = |v: u32| {
+        let mut wm : HashMap<String, u32> = HashMap::new();
+        let (_a, _) = wm.insert("a".to_string(), v);
+        let mut saved = Vec::new();
+        wm.save(&mut saved).unwrap();
+        saved
+    };
+    // the header of one file with the entries of both
+    let (first, second) = (saved(1), saved(2));
+    let mut both = first[..8].to_vec();
+    2u64.write_to(&mut both).unwrap();
+    both.extend_from_slice(&first[16..]);
+    both.extend_from_slice(&second[16..]);
+
+    let mut marks = Vec::new();
+    let mut restored : HashMap<String, u32> = HashMap::restore(&both[..], |_: &String, mark| marks.push(mark)).unwrap();
+    assert_eq!(restored.len(), 1);
+    assert_eq!(restored.get("a"), Some(&2));
+
+    // the mark of the replaced entry refers to nothing
+    drop(marks.remove(0));
+    assert!(!restored.needs_gc());
+    restored.assert_invariants();
+
+    let pinned : HashMap<String, u32> = HashMap::restore_pinned(&both[..]).unwrap();
+    assert_eq!(pinned.len(), 1);
+    pinned.assert_invariants();
+}
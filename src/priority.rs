@@ -0,0 +1,202 @@
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+
+use super::atomic::AtomicUsize;
+use super::atomic::Ordering;
+use super::atomic::Arc;
+use super::mark::Marker;
+use super::GcReport;
+use super::MarkOnDrop;
+
+// heap position of an entry, outdated once the key is inserted again
+struct Queued<K, P> {
+    priority: P,
+    seq:      u64,
+    key:      K,
+}
+
+impl<K, P: Ord> Ord for Queued<K, P> {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        // equal priorities come out in insertion order
+        self.priority.cmp(&other.priority).then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl<K, P: Ord> PartialOrd for Queued<K, P> {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K, P: Ord> PartialEq for Queued<K, P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl<K, P: Ord> Eq for Queued<K, P> {}
+
+/// Keyed priority queue. `pop` and `peek` return the live entry with the
+/// highest priority, entries whose mark dropped are discarded on the way.
+pub struct PriorityMap<K, P, V> {
+    heap:    BinaryHeap<Queued<K, P>>,
+    entries: std::collections::HashMap<K, (u64, V, Arc<Marker>)>,
+    seq:     u64,
+    gc:      Arc<AtomicUsize>,
+}
+
+impl<K, P, V> Default for PriorityMap<K, P, V>
+    where K: std::cmp::Eq + std::hash::Hash,
+          P: Ord,
+{
+    fn default() -> Self {
+        PriorityMap {
+            heap:    BinaryHeap::new(),
+            entries: std::collections::HashMap::new(),
+            seq:     0,
+            gc:      Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl<K, P, V> PriorityMap<K, P, V>
+    where K: std::cmp::Eq + std::hash::Hash,
+          P: Ord,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K, P, V> PriorityMap<K, P, V>
+    where K: std::cmp::Eq + std::hash::Hash + Clone,
+          P: Ord,
+{
+    /// Insert or replace the entry for `k`, queued at `priority`.
+    pub fn insert(&mut self, k: K, priority: P, v: V) -> (MarkOnDrop, Option<V>) {
+        self.maybe_gc();
+        let mark = MarkOnDrop::new(&self.gc);
+        let seq = self.seq;
+        self.seq += 1;
+        self.heap.push(Queued{priority, seq, key: k.clone()});
+        let old = match self.entries.insert(k, (seq, v, mark.marker.clone())) {
+            Some((_, v, marker)) if marker.detach(&self.gc) => Some(v),
+            _ => None,
+        };
+        (mark, old)
+    }
+
+    pub fn get<Q>(&self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        match self.entries.get(k) {
+            Some((_, v, marker)) if !marker.is_dead() => Some(v),
+            _ => None,
+        }
+    }
+
+    // drop heap positions until the top one belongs to a live entry
+    fn settle(&mut self) {
+        while let Some(top) = self.heap.peek() {
+            let dead = match self.entries.get(&top.key) {
+                Some(&(seq, _, _)) if seq != top.seq => false,
+                Some((_, _, marker)) => {
+                    if !marker.collect(&self.gc) {
+                        return;
+                    }
+                    true
+                },
+                None => false,
+            };
+            if let Some(top) = self.heap.pop() {
+                if dead {
+                    self.entries.remove(&top.key);
+                }
+            }
+        }
+    }
+
+    /// The live entry that `pop` would return.
+    pub fn peek(&mut self) -> Option<(&K, &P, &V)> {
+        self.settle();
+        let top = self.heap.peek()?;
+        let (_, v, _) = &self.entries[&top.key];
+        Some((&top.key, &top.priority, v))
+    }
+
+    /// Remove the live entry with the highest priority. Its mark no longer
+    /// refers to anything.
+    pub fn pop(&mut self) -> Option<(K, P, V)> {
+        self.settle();
+        let top = self.heap.pop()?;
+        let (_, v, marker) = self.entries.remove(&top.key)?;
+        marker.detach(&self.gc);
+        Some((top.key, top.priority, v))
+    }
+
+    /// Number of stored entries, including dead ones that were not
+    /// discarded yet.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    fn maybe_gc(&mut self) {
+        // replaced entries leave their old heap positions behind
+        if self.gc.load(Ordering::SeqCst) > self.len() / 2 || self.heap.len() > 2 * self.len() + 16 {
+            self.gc();
+        }
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        let start = std::time::Instant::now();
+        let scanned = self.len();
+        let gc = &self.gc;
+        self.entries.retain(|_, (_, _, marker)| !marker.collect(gc));
+        let entries = &self.entries;
+        self.heap.retain(|q| entries.get(&q.key).map(|&(seq, _, _)| seq == q.seq).unwrap_or(false));
+        GcReport {
+            scanned,
+            collected: scanned - self.len(),
+            elapsed:   start.elapsed(),
+        }
+    }
+
+    /// Live entries in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().filter_map(|(k, (_, v, marker))|{
+            if marker.is_dead() {
+                None
+            } else {
+                Some((k, v))
+            }
+        })
+    }
+}
+
+
+#[test]
+fn priority_pop() {
+    let mut q : PriorityMap<&'static str, u32, u32> = PriorityMap::new();
+    let (_a, _) = q.insert("a", 1, 10);
+    let (b, _)  = q.insert("b", 5, 20);
+    let (_c, _) = q.insert("c", 3, 30);
+    let (_d, _) = q.insert("d", 3, 40);
+
+    drop(b);
+    assert_eq!(q.peek(), Some((&"c", &3, &30)));
+    assert_eq!(q.len(), 3);
+
+    // requeueing moves the entry and outdates its old position
+    let (_a, old) = q.insert("a", 9, 11);
+    assert_eq!(old, Some(10));
+    assert_eq!(q.pop(), Some(("a", 9, 11)));
+    assert_eq!(q.pop(), Some(("c", 3, 30)));
+    assert_eq!(q.pop(), Some(("d", 3, 40)));
+    assert_eq!(q.pop(), None);
+    assert_eq!(q.heap.len(), 0);
+}
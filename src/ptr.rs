@@ -0,0 +1,82 @@
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// Key wrapper comparing and hashing an `Arc` by the address it points to,
+/// so any object can key a map by identity, without `T: Hash + Eq`.
+///
+/// The key holds a strong reference, so the object lives at least as long
+/// as its entry is stored. See `WeakKeyHashMap` for entries that end with
+/// the object instead.
+pub struct ByPtr<T: ?Sized>(pub Arc<T>);
+
+impl<T: ?Sized> ByPtr<T> {
+    /// A key for `arc`, cloning the reference.
+    pub fn of(arc: &Arc<T>) -> Self {
+        ByPtr(arc.clone())
+    }
+
+    fn addr(&self) -> *const () {
+        Arc::as_ptr(&self.0) as *const ()
+    }
+}
+
+impl<T: ?Sized> Clone for ByPtr<T> {
+    fn clone(&self) -> Self {
+        ByPtr(self.0.clone())
+    }
+}
+
+impl<T: ?Sized> PartialEq for ByPtr<T> {
+    fn eq(&self, other: &Self) -> bool {
+        // only the address, vtables of the same type may differ between units
+        self.addr() == other.addr()
+    }
+}
+
+impl<T: ?Sized> Eq for ByPtr<T> {}
+
+impl<T: ?Sized> Hash for ByPtr<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.addr().hash(state)
+    }
+}
+
+impl<T: ?Sized> Deref for ByPtr<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: ?Sized> From<Arc<T>> for ByPtr<T> {
+    fn from(arc: Arc<T>) -> Self {
+        ByPtr(arc)
+    }
+}
+
+impl<T: ?Sized + std::fmt::Debug> std::fmt::Debug for ByPtr<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ByPtr({:p}, {:?})", self.addr(), &*self.0)
+    }
+}
+
+
+#[test]
+fn by_ptr_keys() {
+    struct Conn;
+    let a = Arc::new(Conn);
+    let b = Arc::new(Conn);
+
+    let mut wm : super::HashMap<ByPtr<Conn>, u32> = super::HashMap::new();
+    let (_ma, _) = wm.insert(ByPtr::of(&a), 1);
+    let (mb, _)  = wm.insert(b.clone().into(), 2);
+    assert_eq!(wm.get(&ByPtr::of(&a)), Some(&1));
+    assert_eq!(wm.get(&ByPtr::of(&b)), Some(&2));
+    assert_eq!(wm.get(&ByPtr(Arc::new(Conn))), None);
+
+    drop(mb);
+    assert_eq!(wm.get(&ByPtr::of(&b)), None);
+    assert_eq!(Arc::strong_count(&b), 1);
+}
@@ -0,0 +1,27 @@
+use super::MarkOnDrop;
+
+/// Owns a group of marks. Dropping the scope drops all of them, removing
+/// every entry created under it, across any number of maps.
+#[derive(Default)]
+pub struct GcScope {
+    marks: Vec<MarkOnDrop>,
+}
+
+impl GcScope {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adopt a mark, e.g. one returned by a plain `insert`.
+    pub fn push(&mut self, mark: MarkOnDrop) {
+        self.marks.push(mark);
+    }
+
+    pub fn len(&self) -> usize {
+        self.marks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.marks.is_empty()
+    }
+}
@@ -0,0 +1,70 @@
+use super::GcReport;
+use super::HashMap;
+use super::MarkOnDrop;
+
+/// Set variant, membership ends when the mark returned by `insert` drops.
+pub struct HashSet<K> {
+    map: HashMap<K, ()>,
+}
+
+impl<K> Default for HashSet<K>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    fn default() -> Self {
+        HashSet{map: HashMap::new()}
+    }
+}
+
+impl<K> HashSet<K>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K> HashSet<K>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    /// Insert `k`. If it was already a member, the old mark no longer
+    /// controls membership.
+    pub fn insert(&mut self, k: K) -> MarkOnDrop {
+        self.map.insert(k, ()).0
+    }
+
+    pub fn contains<Q>(&self, k: &Q) -> bool
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.map.get_live(k).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &K> {
+        self.map.iter().map(|(k, _)| k)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        self.map.gc()
+    }
+}
+
+
+#[test]
+fn set_membership() {
+    let mut set : HashSet<&'static str> = HashSet::new();
+    let a = set.insert("a");
+    let _b = set.insert("b");
+    assert!(set.contains("a"));
+
+    drop(a);
+    assert!(!set.contains("a"));
+    assert_eq!(set.iter().collect::<Vec<_>>(), vec![&"b"]);
+}
@@ -0,0 +1,114 @@
+use std::hash::BuildHasher;
+
+use super::HashMap;
+use super::LiveView;
+
+/// Anything keys can be checked against, see `HashMap::intersect_keys` and
+/// `HashMap::difference_keys`. Maps of this crate only contain live keys.
+pub trait KeySet<K> {
+    fn contains_key(&self, k: &K) -> bool;
+}
+
+impl<K, S> KeySet<K> for std::collections::HashSet<K, S>
+    where K: std::cmp::Eq + std::hash::Hash,
+          S: BuildHasher,
+{
+    fn contains_key(&self, k: &K) -> bool {
+        self.contains(k)
+    }
+}
+
+impl<K, V, S> KeySet<K> for std::collections::HashMap<K, V, S>
+    where K: std::cmp::Eq + std::hash::Hash,
+          S: BuildHasher,
+{
+    fn contains_key(&self, k: &K) -> bool {
+        std::collections::HashMap::contains_key(self, k)
+    }
+}
+
+impl<K: Ord> KeySet<K> for std::collections::BTreeSet<K> {
+    fn contains_key(&self, k: &K) -> bool {
+        self.contains(k)
+    }
+}
+
+impl<K, V> KeySet<K> for HashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    fn contains_key(&self, k: &K) -> bool {
+        HashMap::contains_key(self, k)
+    }
+}
+
+impl<'a, K, V> KeySet<K> for LiveView<'a, K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    fn contains_key(&self, k: &K) -> bool {
+        LiveView::contains_key(self, k)
+    }
+}
+
+impl<K, V> HashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    /// Live keys that `other` contains too.
+    pub fn intersect_keys<'a, S>(&'a self, other: &'a S) -> impl Iterator<Item = &'a K>
+        where S: KeySet<K>,
+    {
+        self.iter().map(|(k, _)| k).filter(move |k| other.contains_key(k))
+    }
+
+    /// Live keys that `other` does not contain.
+    pub fn difference_keys<'a, S>(&'a self, other: &'a S) -> impl Iterator<Item = &'a K>
+        where S: KeySet<K>,
+    {
+        self.iter().map(|(k, _)| k).filter(move |k| !other.contains_key(k))
+    }
+
+    /// A new map with the live entries of both maps, this one's win where
+    /// both have the key. Entries stay tied to the marks they already have,
+    /// in either map, like with `clone`.
+    pub fn union_with(&self, other: &HashMap<K, V>) -> HashMap<K, V>
+        where K: Clone,
+              V: Clone,
+    {
+        let mut union = HashMap::new();
+        union.config = self.config;
+        // nothing may be replaced, that would detach a mark both maps share
+        for map in [self, other] {
+            for (k, slot) in map.v.iter() {
+                if !slot.is_dead() && !union.v.contains_key(k) {
+                    union.adopt(k.clone(), slot.clone(), &map.gc);
+                }
+            }
+        }
+        union
+    }
+}
+
+
+#[test]
+fn set_operations() {
+    let mut wm : HashMap<u32, &'static str> = HashMap::new();
+    let (a, _)  = wm.insert(1, "a");
+    let (_b, _) = wm.insert(2, "b");
+    let (_c, _) = wm.insert(3, "c");
+    drop(a);
+
+    let allow : std::collections::HashSet<u32> = vec![1, 2].into_iter().collect();
+    assert_eq!(wm.intersect_keys(&allow).collect::<Vec<_>>(), vec![&2]);
+    assert_eq!(wm.difference_keys(&allow).collect::<Vec<_>>(), vec![&3]);
+
+    let mut other : HashMap<u32, &'static str> = HashMap::new();
+    let (_d, _) = other.insert(3, "other c");
+    let (e, _)  = other.insert(4, "d");
+    let mut union = wm.union_with(&other);
+    assert_eq!(union.len(), 3);
+    assert_eq!(union.get(&3), Some(&"c"));
+
+    // a mark of either side still ends its entry in the union
+    drop(e);
+    assert_eq!(union.get(&4), None);
+    assert_eq!(wm.intersect_keys(&other).collect::<Vec<_>>(), vec![&3]);
+}
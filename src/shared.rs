@@ -0,0 +1,133 @@
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::sync::RwLockReadGuard;
+use std::sync::RwLockWriteGuard;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::Entry;
+use super::GcReport;
+use super::HashMap;
+use super::MarkOnDrop;
+
+/// Cheaply clonable handle to a `HashMap` behind an `RwLock`.
+///
+/// Lookups take the read lock and skip dead entries without removing them,
+/// everything that may remove an entry takes the write lock.
+pub struct SharedHashMap<K, V> {
+    inner: Arc<RwLock<HashMap<K, V>>>,
+}
+
+impl<K, V> Clone for SharedHashMap<K, V> {
+    fn clone(&self) -> Self {
+        SharedHashMap{inner: self.inner.clone()}
+    }
+}
+
+impl<K,V> Default for SharedHashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    fn default() -> Self {
+        SharedHashMap{inner: Arc::new(RwLock::new(HashMap::new()))}
+    }
+}
+
+impl<K, V> From<HashMap<K, V>> for SharedHashMap<K, V> {
+    fn from(map: HashMap<K, V>) -> Self {
+        SharedHashMap{inner: Arc::new(RwLock::new(map))}
+    }
+}
+
+impl<K, V> SharedHashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K,V> SharedHashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    // the map stays consistent if a user closure panics, so poisoning is ignored.
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<K, V>> {
+        self.inner.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<K, V>> {
+        self.inner.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn insert(&self, k: K, v: V) -> (MarkOnDrop, Option<V>) {
+        self.write().insert(k, v)
+    }
+
+    pub fn get_cloned<Q>(&self, k: &Q) -> Option<V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+              V: Clone,
+    {
+        self.read().get_live(k).cloned()
+    }
+
+    /// Run `f` on the entry for `k` while holding the write lock.
+    pub fn with_entry<R, F>(&self, k: K, f: F) -> R
+        where F: FnOnce(Entry<'_, K, V>) -> R,
+    {
+        f(self.write().entry(k))
+    }
+
+    /// Run `f` on the whole map while holding the write lock.
+    pub fn with<R, F>(&self, f: F) -> R
+        where F: FnOnce(&mut HashMap<K, V>) -> R,
+    {
+        f(&mut self.write())
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    pub fn gc(&self) -> GcReport {
+        self.write().gc()
+    }
+
+    /// Sweep the map every `interval` on a background thread. The thread
+    /// exits at the first tick after the last handle was dropped.
+    pub fn spawn_gc(&self, interval: Duration) -> JoinHandle<()>
+        where K: Send + Sync + 'static,
+              V: Send + Sync + 'static,
+    {
+        let map = Arc::downgrade(&self.inner);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            match map.upgrade() {
+                Some(map) => { map.write().unwrap_or_else(|e| e.into_inner()).gc(); },
+                None => break,
+            }
+        })
+    }
+}
+
+
+#[test]
+fn shared_handles() {
+    let wm : SharedHashMap<u32, String> = SharedHashMap::new();
+    let other = wm.clone();
+
+    let (mark, _) = wm.insert(1, "hello".to_string());
+    assert_eq!(other.get_cloned(&1), Some("hello".to_string()));
+
+    let len = other.with_entry(1, |e| match e {
+        Entry::Occupied(mut e) => { e.get_mut().push_str(" world"); e.get().len() },
+        Entry::Vacant(_)       => 0,
+    });
+    assert_eq!(len, 11);
+
+    std::thread::spawn(move || drop(mark)).join().unwrap();
+    assert_eq!(wm.get_cloned(&1), None);
+}
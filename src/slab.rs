@@ -0,0 +1,187 @@
+use super::atomic::AtomicUsize;
+use super::atomic::Ordering;
+use super::atomic::Arc;
+use super::mark::Marker;
+use super::GcReport;
+use super::MarkOnDrop;
+
+/// Handle to a value in a `Slab`. Keys carry the generation of their slot,
+/// so a key stays invalid after its slot was collected and reused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Key {
+    index:      usize,
+    generation: u32,
+}
+
+impl Key {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+enum Slot<V> {
+    Occupied(V, Arc<Marker>),
+    Vacant(Option<usize>),
+}
+
+/// Dense variant indexed by generational keys instead of hashing.
+pub struct Slab<V> {
+    slots: Vec<(u32, Slot<V>)>,
+    free:  Option<usize>,
+    len:   usize,
+    gc:    Arc<AtomicUsize>,
+}
+
+impl<V> Default for Slab<V> {
+    fn default() -> Self {
+        Slab {
+            slots: Vec::new(),
+            free:  None,
+            len:   0,
+            gc:    Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl<V> Slab<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<V> Slab<V> {
+    pub fn insert(&mut self, v: V) -> (Key, MarkOnDrop) {
+        self.maybe_gc();
+        let mark = MarkOnDrop::new(&self.gc);
+        let slot = Slot::Occupied(v, mark.marker.clone());
+
+        let index = match self.free {
+            Some(index) => {
+                if let Slot::Vacant(next) = self.slots[index].1 {
+                    self.free = next;
+                }
+                self.slots[index].1 = slot;
+                index
+            },
+            None => {
+                self.slots.push((0, slot));
+                self.slots.len() - 1
+            },
+        };
+        self.len += 1;
+
+        (Key{index, generation: self.slots[index].0}, mark)
+    }
+
+    pub fn get(&mut self, key: Key) -> Option<&V> {
+        self.remove_dead(key);
+        match self.slots.get(key.index) {
+            Some(&(generation, Slot::Occupied(ref v, _))) if generation == key.generation => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut V> {
+        self.remove_dead(key);
+        match self.slots.get_mut(key.index) {
+            Some(&mut (generation, Slot::Occupied(ref mut v, _))) if generation == key.generation => Some(v),
+            _ => None,
+        }
+    }
+
+    fn remove_dead(&mut self, key: Key) {
+        let dead = match self.slots.get(key.index) {
+            Some(&(generation, Slot::Occupied(_, ref marker))) => {
+                generation == key.generation && marker.collect(&self.gc)
+            },
+            _ => false,
+        };
+        if dead {
+            self.release(key.index);
+        }
+    }
+
+    fn release(&mut self, index: usize) {
+        let slot = &mut self.slots[index];
+        slot.0 = slot.0.wrapping_add(1);
+        slot.1 = Slot::Vacant(self.free);
+        self.free = Some(index);
+        self.len -= 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    fn maybe_gc(&mut self) {
+        if self.gc.load(Ordering::SeqCst) > self.len() / 2 {
+            self.gc();
+        }
+    }
+
+    /// Collect every dead slot, making it available to the next inserts.
+    pub fn gc(&mut self) -> GcReport {
+        let start = std::time::Instant::now();
+        let scanned = self.len();
+        for index in 0..self.slots.len() {
+            let dead = match self.slots[index].1 {
+                Slot::Occupied(_, ref marker) => marker.collect(&self.gc),
+                Slot::Vacant(_) => false,
+            };
+            if dead {
+                self.release(index);
+            }
+        }
+        GcReport {
+            scanned,
+            collected: scanned - self.len(),
+            elapsed:   start.elapsed(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Key, &V)> {
+        self.slots.iter().enumerate().filter_map(|(index, &(generation, ref slot))|{
+            match *slot {
+                Slot::Occupied(ref v, ref marker) if !marker.is_dead() => {
+                    Some((Key{index, generation}, v))
+                },
+                _ => None,
+            }
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Key, &mut V)> {
+        self.slots.iter_mut().enumerate().filter_map(|(index, &mut (generation, ref mut slot))|{
+            match *slot {
+                Slot::Occupied(ref mut v, ref marker) if !marker.is_dead() => {
+                    Some((Key{index, generation}, v))
+                },
+                _ => None,
+            }
+        })
+    }
+}
+
+
+#[test]
+fn slab_reuse() {
+    let mut slab : Slab<&'static str> = Slab::new();
+    let (a, mark_a) = slab.insert("a");
+    let (b, _mark_b) = slab.insert("b");
+    assert_eq!(slab.get(a), Some(&"a"));
+
+    drop(mark_a);
+    assert_eq!(slab.gc().collected, 1);
+    assert_eq!(slab.len(), 1);
+
+    let (c, _mark_c) = slab.insert("c");
+    assert_eq!(c.index(), a.index());
+    assert_eq!(slab.get(a), None);
+    assert_eq!(slab.get(c), Some(&"c"));
+    assert_eq!(slab.iter().map(|(_, v)| *v).collect::<Vec<_>>(), vec!["c", "b"]);
+    assert_eq!(slab.get(b), Some(&"b"));
+}
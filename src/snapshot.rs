@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use super::Slot;
+
+/// Immutable copy of the live entries of a map at one point in time, see
+/// `HashMap::snapshot`. Clones share the same storage.
+pub struct Snapshot<K, V> {
+    v: Arc<std::collections::HashMap<K, V>>,
+}
+
+impl<K, V> Clone for Snapshot<K, V> {
+    fn clone(&self) -> Self {
+        Snapshot{v: self.v.clone()}
+    }
+}
+
+impl<K, V> Snapshot<K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    pub(crate) fn new(v: std::collections::HashMap<K, V>) -> Self {
+        Snapshot{v: Arc::new(v)}
+    }
+
+    pub fn get<Q>(&self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.v.get(k)
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.v.contains_key(k)
+    }
+
+    pub fn len(&self) -> usize {
+        self.v.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.v.iter()
+    }
+}
+
+/// Read-only view of the live entries of a map, see `HashMap::as_inner`.
+/// Unlike a `Snapshot` nothing is copied, so it borrows the map.
+pub struct LiveView<'a, K: 'a, V: 'a> {
+    v: &'a std::collections::HashMap<K, Slot<V>>,
+}
+
+impl<'a, K, V> Clone for LiveView<'a, K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, K, V> Copy for LiveView<'a, K, V> {}
+
+impl<'a, K, V> LiveView<'a, K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    pub(crate) fn new(v: &'a std::collections::HashMap<K, Slot<V>>) -> Self {
+        LiveView{v}
+    }
+
+    pub fn get<Q>(&self, k: &Q) -> Option<&'a V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        match self.v.get(k) {
+            Some(slot) if !slot.is_dead() => Some(&slot.value),
+            _ => None,
+        }
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.get(k).is_some()
+    }
+
+    /// Number of live entries. Walks the whole map.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'a K, &'a V)> {
+        self.v.iter().filter_map(|(k, slot)| if slot.is_dead() { None } else { Some((k, &slot.value)) })
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &'a K> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &'a V> {
+        self.iter().map(|(_, v)| v)
+    }
+}
+
+
+#[test]
+fn snapshot_frozen() {
+    let mut wm : super::HashMap<u32, &'static str> = super::HashMap::new();
+    let (a, _) = wm.insert(1, "a");
+    let (_b, _) = wm.insert(2, "b");
+    drop(a);
+
+    let snapshot = wm.snapshot();
+    let (_c, _) = wm.insert(3, "c");
+    let (_b, _) = wm.insert(2, "bb");
+
+    let other = snapshot.clone();
+    let report = std::thread::spawn(move || other.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>()).join().unwrap();
+    assert_eq!(report, vec![(2, "b")]);
+    assert_eq!(snapshot.get(&2), Some(&"b"));
+    assert!(!snapshot.contains_key(&1));
+    assert_eq!(snapshot.len(), 1);
+}
+
+#[test]
+fn live_view() {
+    let mut wm : super::HashMap<u32, &'static str> = super::HashMap::new();
+    let (a, _) = wm.insert(1, "a");
+    let (_b, _) = wm.insert(2, "b");
+    drop(a);
+
+    let view = wm.as_inner();
+    assert_eq!(view.get(&2), Some(&"b"));
+    assert!(!view.contains_key(&1));
+    assert_eq!(view.len(), 1);
+    assert_eq!(view.keys().collect::<Vec<_>>(), vec![&2]);
+
+    let std = wm.into_std();
+    assert_eq!(std.len(), 1);
+    assert_eq!(std[&2], "b");
+}
@@ -0,0 +1,331 @@
+use super::atomic::AtomicUsize;
+use super::atomic::Ordering;
+use super::atomic::Arc;
+use super::mark::Marker;
+use super::GcReport;
+use super::MarkOnDrop;
+
+/// A value together with the marker of its entry, as kept in a `GcStorage`.
+/// Opaque, so a storage can only move it around.
+pub struct Stored<V> {
+    pub(crate) value:  V,
+    pub(crate) marker: Arc<Marker>,
+}
+
+/// A keyed container that `GcMap` can keep its entries in. Implemented for
+/// the std maps, other containers only need these few operations.
+pub trait GcStorage<K, E>: Default + GcLookup<K, E> {
+    fn insert(&mut self, k: K, e: E) -> Option<E>;
+    fn retain<F: FnMut(&K, &mut E) -> bool>(&mut self, f: F);
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn for_each<F: FnMut(&K, &E)>(&self, f: F);
+}
+
+/// Lookup of a `GcStorage` by `Q`, the key itself or a borrowed form of it
+/// as with the std maps.
+pub trait GcLookup<Q: ?Sized, E> {
+    fn get(&self, k: &Q) -> Option<&E>;
+    fn get_mut(&mut self, k: &Q) -> Option<&mut E>;
+    fn remove(&mut self, k: &Q) -> Option<E>;
+}
+
+impl<K, E, S> GcStorage<K, E> for std::collections::HashMap<K, E, S>
+    where K: std::cmp::Eq + std::hash::Hash,
+          S: std::hash::BuildHasher + Default,
+{
+    fn insert(&mut self, k: K, e: E) -> Option<E> {
+        std::collections::HashMap::insert(self, k, e)
+    }
+
+    fn retain<F: FnMut(&K, &mut E) -> bool>(&mut self, f: F) {
+        std::collections::HashMap::retain(self, f)
+    }
+
+    fn len(&self) -> usize {
+        std::collections::HashMap::len(self)
+    }
+
+    fn for_each<F: FnMut(&K, &E)>(&self, mut f: F) {
+        self.iter().for_each(|(k, e)| f(k, e))
+    }
+}
+
+impl<K, Q, E, S> GcLookup<Q, E> for std::collections::HashMap<K, E, S>
+    where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+          K: std::cmp::Eq + std::hash::Hash + std::borrow::Borrow<Q>,
+          S: std::hash::BuildHasher,
+{
+    fn get(&self, k: &Q) -> Option<&E> {
+        std::collections::HashMap::get(self, k)
+    }
+
+    fn get_mut(&mut self, k: &Q) -> Option<&mut E> {
+        std::collections::HashMap::get_mut(self, k)
+    }
+
+    fn remove(&mut self, k: &Q) -> Option<E> {
+        std::collections::HashMap::remove(self, k)
+    }
+}
+
+impl<K, E> GcStorage<K, E> for std::collections::BTreeMap<K, E>
+    where K: std::cmp::Ord,
+{
+    fn insert(&mut self, k: K, e: E) -> Option<E> {
+        std::collections::BTreeMap::insert(self, k, e)
+    }
+
+    fn retain<F: FnMut(&K, &mut E) -> bool>(&mut self, f: F) {
+        std::collections::BTreeMap::retain(self, f)
+    }
+
+    fn len(&self) -> usize {
+        std::collections::BTreeMap::len(self)
+    }
+
+    fn for_each<F: FnMut(&K, &E)>(&self, mut f: F) {
+        self.iter().for_each(|(k, e)| f(k, e))
+    }
+}
+
+impl<K, Q, E> GcLookup<Q, E> for std::collections::BTreeMap<K, E>
+    where Q: ?Sized + std::cmp::Ord,
+          K: std::cmp::Ord + std::borrow::Borrow<Q>,
+{
+    fn get(&self, k: &Q) -> Option<&E> {
+        std::collections::BTreeMap::get(self, k)
+    }
+
+    fn get_mut(&mut self, k: &Q) -> Option<&mut E> {
+        std::collections::BTreeMap::get_mut(self, k)
+    }
+
+    fn remove(&mut self, k: &Q) -> Option<E> {
+        std::collections::BTreeMap::remove(self, k)
+    }
+}
+
+/// The mark and sweep core of the map, over any `GcStorage`. The storage
+/// decides lookup and iteration order, e.g. a `std::collections::BTreeMap`
+/// gives an ordered map.
+///
+/// `BTreeMap`, `OrderedHashMap`, `LruHashMap` and `WeightedHashMap` are
+/// built on it. It has no expiry, pinning or hooks, so `HashMap` keeps its
+/// own slots for those, and the variants wrapping a `HashMap` get them from
+/// there.
+pub struct GcMap<K, V, S = std::collections::HashMap<K, Stored<V>>> {
+    pub(crate) storage: S,
+    pub(crate) gc:      Arc<AtomicUsize>,
+    _kv:                std::marker::PhantomData<fn(K) -> V>,
+}
+
+impl<K, V, S> Default for GcMap<K, V, S>
+    where S: GcStorage<K, Stored<V>>,
+{
+    fn default() -> Self {
+        GcMap {
+            storage: S::default(),
+            gc:      Arc::new(AtomicUsize::new(0)),
+            _kv:     std::marker::PhantomData,
+        }
+    }
+}
+
+impl<K, V, S> GcMap<K, V, S>
+    where S: GcStorage<K, Stored<V>>,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K, V, S> GcMap<K, V, S>
+    where S: GcStorage<K, Stored<V>>,
+{
+    /// Replacing a live entry leaves it where the storage has it, a dead
+    /// one is removed first, so e.g. an ordered storage appends the new one.
+    pub fn insert(&mut self, k: K, v: V) -> (MarkOnDrop, Option<V>) {
+        self.maybe_gc();
+        self.remove_dead(&k);
+        let (mark, old) = self.replace(k, v);
+        (mark, old.and_then(|(v, live)| if live { Some(v) } else { None }))
+    }
+
+    /// Insert without collecting anything first. The replaced value, if
+    /// any, comes back with whether it was live.
+    pub(crate) fn replace(&mut self, k: K, v: V) -> (MarkOnDrop, Option<(V, bool)>) {
+        let mark = MarkOnDrop::new(&self.gc);
+        let old = self.storage.insert(k, Stored{value: v, marker: mark.marker.clone()});
+        let gc = &self.gc;
+        (mark, old.map(|old| (old.value, old.marker.detach(gc))))
+    }
+
+    pub fn get<Q>(&mut self, k: &Q) -> Option<&V>
+        where Q: ?Sized,
+              S: GcLookup<Q, Stored<V>>,
+    {
+        self.remove_dead(k);
+        self.storage.get(k).map(|e| &e.value)
+    }
+
+    pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+        where Q: ?Sized,
+              S: GcLookup<Q, Stored<V>>,
+    {
+        self.remove_dead(k);
+        self.storage.get_mut(k).map(|e| &mut e.value)
+    }
+
+    /// Lookup that leaves a dead entry in place.
+    pub(crate) fn get_live<Q>(&self, k: &Q) -> Option<&V>
+        where Q: ?Sized,
+              S: GcLookup<Q, Stored<V>>,
+    {
+        match self.storage.get(k) {
+            Some(e) if !e.marker.is_dead() => Some(&e.value),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get_live_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+        where Q: ?Sized,
+              S: GcLookup<Q, Stored<V>>,
+    {
+        match self.storage.get_mut(k) {
+            Some(e) if !e.marker.is_dead() => Some(&mut e.value),
+            _ => None,
+        }
+    }
+
+    /// Collects the entry at `k` if it is dead and hands back its value.
+    pub(crate) fn remove_dead<Q>(&mut self, k: &Q) -> Option<V>
+        where Q: ?Sized,
+              S: GcLookup<Q, Stored<V>>,
+    {
+        if self.storage.get(k).map(|e| e.marker.collect(&self.gc)).unwrap_or(false) {
+            self.storage.remove(k).map(|e| e.value)
+        } else {
+            None
+        }
+    }
+
+    /// Removes the entry at `k` whatever its state, its outstanding mark
+    /// no longer keeps anything alive. Returns the value with whether it
+    /// was live.
+    pub(crate) fn take<Q>(&mut self, k: &Q) -> Option<(V, bool)>
+        where Q: ?Sized,
+              S: GcLookup<Q, Stored<V>>,
+    {
+        let e = self.storage.remove(k)?;
+        let live = e.marker.detach(&self.gc);
+        Some((e.value, live))
+    }
+
+    /// Marks dropped but not collected yet.
+    pub(crate) fn pending(&self) -> usize {
+        self.gc.load(Ordering::SeqCst)
+    }
+
+    /// Number of stored entries, including dead ones that were not
+    /// collected yet.
+    pub fn len(&self) -> usize {
+        self.storage.len()
+    }
+
+    /// True if there is no live entry.
+    pub fn is_empty(&self) -> bool {
+        let mut empty = true;
+        self.for_each(|_, _| empty = false);
+        empty
+    }
+
+    fn maybe_gc(&mut self) {
+        if self.pending() > self.len() / 2 {
+            self.gc();
+        }
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        self.gc_with(|_, _| ())
+    }
+
+    /// Sweep that calls `f` on every value it collects, for wrappers that
+    /// keep more state per entry.
+    pub(crate) fn gc_with<F>(&mut self, mut f: F) -> GcReport
+        where F: FnMut(&K, &mut V),
+    {
+        let start = std::time::Instant::now();
+        let scanned = self.storage.len();
+        let gc = &self.gc;
+        self.storage.retain(|k, e| {
+            if e.marker.collect(gc) {
+                f(k, &mut e.value);
+                false
+            } else {
+                true
+            }
+        });
+        GcReport {
+            scanned,
+            collected: scanned - self.storage.len(),
+            elapsed:   start.elapsed(),
+        }
+    }
+
+    /// Call `f` on every live entry, in the order of the storage.
+    pub fn for_each<F>(&self, mut f: F)
+        where F: FnMut(&K, &V),
+    {
+        self.storage.for_each(|k, e| if !e.marker.is_dead() { f(k, &e.value) });
+    }
+
+    /// The underlying container, e.g. for lookups the trait does not cover.
+    /// Dead entries are still in it.
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+}
+
+
+#[test]
+fn gc_storage() {
+    fn exercise<S: GcStorage<u32, Stored<u32>>>() -> Vec<u32> {
+        let mut wm : GcMap<u32, u32, S> = GcMap::new();
+        let (_c, _) = wm.insert(3, 30);
+        let (a, _)  = wm.insert(1, 10);
+        let (_b, _) = wm.insert(2, 20);
+        let (_d, old) = wm.insert(2, 21);
+        assert_eq!(old, Some(20));
+
+        drop(a);
+        assert_eq!(wm.get(&1), None);
+        assert_eq!(wm.gc().collected, 0);
+        assert_eq!(wm.len(), 2);
+        assert!(!wm.is_empty());
+        let mut keys = Vec::new();
+        wm.for_each(|k, _| keys.push(*k));
+        keys
+    }
+
+    let mut hashed = exercise::<std::collections::HashMap<u32, Stored<u32>>>();
+    hashed.sort();
+    assert_eq!(hashed, vec![2, 3]);
+    assert_eq!(exercise::<std::collections::BTreeMap<u32, Stored<u32>>>(), vec![2, 3]);
+}
+
+#[test]
+fn gc_storage_borrowed() {
+    let mut wm : GcMap<String, u32, std::collections::BTreeMap<String, Stored<u32>>> = GcMap::new();
+    let (a, _)  = wm.insert("a".to_string(), 1);
+    let (_b, _) = wm.insert("b".to_string(), 2);
+    assert_eq!(wm.get("b"), Some(&2));
+
+    drop(a);
+    assert_eq!(wm.get("a"), None);
+    assert_eq!(wm.len(), 1);
+}
@@ -0,0 +1,194 @@
+//! Read mostly variant of the map. Writers work on a private map and publish
+//! an immutable copy of its live entries after every change. Readers only
+//! clone the `Arc` of the latest copy, so a lookup never waits for an insert
+//! or a sweep, only for the pointer swap at the end of one.
+//!
+//! Published copies share the markers of the writer map, so an entry whose
+//! mark dropped is invisible to readers right away, without a new version.
+
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::RwLock;
+
+use std::time::Instant;
+
+use super::atomic::Arc;
+use super::mark::Marker;
+use super::MarkOnDrop;
+
+type Table<K, V> = std::collections::HashMap<K, Published<V>>;
+
+// what readers see of a slot. It does not hold the marker like a cloned slot
+// would, so a dropped table never keeps the writer from collecting an entry.
+struct Published<V> {
+    value:    V,
+    marker:   Arc<Marker>,
+    deadline: Option<Instant>,
+    pinned:   bool,
+}
+
+impl<V> Published<V> {
+    fn is_dead(&self) -> bool {
+        !self.pinned && (self.marker.is_dead() || self.deadline.map(|d| Instant::now() >= d).unwrap_or(false))
+    }
+}
+
+pub struct HashMap<K, V> {
+    writer:  Mutex<super::HashMap<K, V>>,
+    current: RwLock<Arc<Table<K, V>>>,
+}
+
+/// One published version of the map.
+pub struct View<K, V> {
+    v: Arc<Table<K, V>>,
+}
+
+impl<K,V> Default for HashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    fn default() -> Self {
+        HashMap {
+            writer:  Mutex::new(super::HashMap::new()),
+            current: RwLock::new(Arc::new(Table::new())),
+        }
+    }
+}
+
+impl<K, V> HashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K,V> HashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash + Clone,
+          V: Clone,
+{
+    /// The latest published version.
+    pub fn load(&self) -> View<K, V> {
+        let v = self.current.read().unwrap_or_else(|e| e.into_inner()).clone();
+        View{v}
+    }
+
+    pub fn get<Q>(&self, k: &Q) -> Option<V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.load().get(k).cloned()
+    }
+
+    pub fn insert(&self, k: K, v: V) -> (MarkOnDrop, Option<V>) {
+        self.update(|map| map.insert(k, v))
+    }
+
+    pub fn gc(&self) -> super::GcReport {
+        self.update(|map| map.gc())
+    }
+
+    /// Run `f` on the writer map and publish the result once afterwards.
+    /// Use this to batch changes, every publish copies all live entries.
+    pub fn update<R, F>(&self, f: F) -> R
+        where F: FnOnce(&mut super::HashMap<K, V>) -> R,
+    {
+        let mut writer = self.writer();
+        let r = f(&mut writer);
+        self.publish(&writer);
+        r
+    }
+
+    fn writer(&self) -> MutexGuard<'_, super::HashMap<K, V>> {
+        self.writer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn publish(&self, writer: &super::HashMap<K, V>) {
+        let table : Table<K, V> = writer.v.iter().filter_map(|(k, slot)|{
+            if slot.is_dead() {
+                None
+            } else {
+                Some((k.clone(), Published {
+                    value:    slot.value.clone(),
+                    marker:   slot.marker.clone(),
+                    deadline: slot.deadline,
+                    pinned:   slot.pinned,
+                }))
+            }
+        }).collect();
+
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(table);
+    }
+}
+
+impl<K, V> Clone for View<K, V> {
+    fn clone(&self) -> Self {
+        View{v: self.v.clone()}
+    }
+}
+
+impl<K, V> View<K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    pub fn get<Q>(&self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        match self.v.get(k) {
+            Some(slot) if !slot.is_dead() => Some(&slot.value),
+            _ => None,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.v.iter().filter_map(|(k, slot)|{
+            if slot.is_dead() {
+                None
+            } else {
+                Some((k, &slot.value))
+            }
+        })
+    }
+}
+
+
+#[test]
+fn swap_publish() {
+    let wm : HashMap<u32, u32> = HashMap::new();
+    let (mark, _) = wm.insert(1, 10);
+
+    let view = wm.load();
+    let (_mark, _) = wm.insert(2, 20);
+    assert_eq!(view.get(&1), Some(&10));
+    assert_eq!(view.get(&2), None);
+    assert_eq!(wm.get(&2), Some(20));
+
+    drop(mark);
+    assert_eq!(view.get(&1), None);
+    assert_eq!(wm.get(&1), None);
+}
+
+#[test]
+fn swap_update() {
+    let wm : HashMap<u32, u32> = HashMap::new();
+    let marks : Vec<MarkOnDrop> = wm.update(|map| (0..10).map(|i| map.insert(i, i).0).collect());
+    assert_eq!(wm.load().iter().count(), 10);
+    drop(marks);
+    assert_eq!(wm.load().iter().count(), 0);
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn swap_publish_collects() {
+    let wm : HashMap<u32, u32> = HashMap::new();
+    let (mark, _) = wm.insert(1, 10);
+    let (replaced, _) = wm.insert(2, 20);
+    let (_mark, _) = wm.insert(2, 21);
+
+    drop(mark);
+    drop(replaced);
+    wm.gc();
+    wm.update(|map| {
+        assert!(!map.needs_gc());
+        map.assert_invariants();
+    });
+}
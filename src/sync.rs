@@ -0,0 +1,611 @@
+//! Concurrent variant of the map. Entries are spread over a fixed number of
+//! independently locked shards, so `insert`, `get` and `entry` take `&self`
+//! and the map can be shared between threads directly.
+
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Waker;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::RwLock;
+use std::sync::RwLockReadGuard;
+use std::sync::RwLockWriteGuard;
+
+use super::Entry;
+use super::MarkOnDrop;
+
+pub struct HashMap<K, V> {
+    shards:  Box<[RwLock<super::HashMap<K, V>>]>,
+    hasher:  RandomState,
+    // loads started by get_or_load that have not finished yet
+    flights: Mutex<std::collections::HashMap<K, Arc<Flight<V>>>>,
+    // tasks in wait_for by the token of their future, and how many keys
+    // they wait on so inserts can skip the lock when nobody waits
+    waiters: Mutex<std::collections::HashMap<K, Vec<(u64, Waker)>>>,
+    waiting: AtomicUsize,
+}
+
+impl<K,V> Default for HashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    fn default() -> Self {
+        let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        Self::with_shards(cpus * 4)
+    }
+}
+
+impl<K, V> HashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a map with `n` shards. More shards means less lock contention
+    /// between threads working on different keys.
+    pub fn with_shards(n: usize) -> Self {
+        HashMap {
+            shards:  (0..n.max(1)).map(|_| RwLock::new(super::HashMap::new())).collect(),
+            hasher:  RandomState::new(),
+            flights: Mutex::new(std::collections::HashMap::new()),
+            waiters: Mutex::new(std::collections::HashMap::new()),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<K,V> HashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    fn shard<Q>(&self, k: &Q) -> usize
+        where Q: ?Sized + Hash,
+    {
+        (self.hasher.hash_one(k) % self.shards.len() as u64) as usize
+    }
+
+    // a panic inside a user closure cannot leave a shard half updated,
+    // so poisoning is ignored rather than propagated to every other thread.
+    fn read(&self, i: usize) -> RwLockReadGuard<'_, super::HashMap<K, V>> {
+        self.shards[i].read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self, i: usize) -> RwLockWriteGuard<'_, super::HashMap<K, V>> {
+        self.shards[i].write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn insert(&self, k: K, v: V) -> (MarkOnDrop, Option<V>) {
+        let i = self.shard(&k);
+        let mut shard = self.write(i);
+        let r = shard.insert(k, v);
+        self.notify(i, &shard);
+        r
+    }
+
+    /// Clone of the live value for `k`. Only takes a read lock, so dead
+    /// entries are skipped here and removed by the next write to the shard.
+    pub fn get<Q>(&self, k: &Q) -> Option<V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+              V: Clone,
+    {
+        self.get_with(k, V::clone)
+    }
+
+    /// Run `f` on the live value for `k` while holding the shard read lock.
+    pub fn get_with<Q, R, F>(&self, k: &Q, f: F) -> Option<R>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+              F: FnOnce(&V) -> R,
+    {
+        self.read(self.shard(k)).get_live(k).map(f)
+    }
+
+    /// Run `f` on the entry for `k` while holding the shard write lock.
+    pub fn entry<R, F>(&self, k: K, f: F) -> R
+        where F: FnOnce(Entry<'_, K, V>) -> R,
+    {
+        let i = self.shard(&k);
+        let mut shard = self.write(i);
+        let r = f(shard.entry(k));
+        self.notify(i, &shard);
+        r
+    }
+
+    fn waiters(&self) -> MutexGuard<'_, std::collections::HashMap<K, Vec<(u64, Waker)>>> {
+        self.waiters.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // wake everyone waiting for a key that is now live in shard `i`. Called
+    // with the shard still locked, and wait_for registers before it checks
+    // the shard, so a waiter is either found here or sees the entry itself.
+    fn notify(&self, i: usize, shard: &super::HashMap<K, V>) {
+        if self.waiting.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let mut waiters = self.waiters();
+        let ready : Vec<_> = waiters.extract_if(|k, _| self.shard(k) == i && shard.contains_key(k)).collect();
+        self.waiting.store(waiters.len(), Ordering::SeqCst);
+        drop(waiters);
+        for (_, wakers) in ready {
+            wakers.into_iter().for_each(|(_, waker)| waker.wake());
+        }
+    }
+
+    /// Resolve once there is a live entry for `k`, e.g. inserted by another
+    /// thread. Entries that appear and die again before the task runs may
+    /// still wake it, but it only resolves while the entry is live.
+    pub fn wait_for(&self, k: &K) -> WaitFor<'_, K, V>
+        where K: Clone,
+    {
+        WaitFor {
+            map:   self,
+            key:   k.clone(),
+            token: super::atomic::next_id(),
+        }
+    }
+
+    /// Call `f` on every live entry, one shard at a time.
+    pub fn for_each<F>(&self, mut f: F)
+        where F: FnMut(&K, &V),
+    {
+        for i in 0..self.shards.len() {
+            for (k, v) in self.read(i).iter() {
+                f(k, v);
+            }
+        }
+    }
+
+    fn flights(&self) -> MutexGuard<'_, std::collections::HashMap<K, Arc<Flight<V>>>> {
+        self.flights.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Return the live value for `k`, or run `load` to produce it.
+    ///
+    /// Concurrent calls for the same key share one load: the first caller
+    /// runs its loader and gets the mark of the inserted entry, the others
+    /// wait for it and get the value only. If the loading caller is dropped
+    /// before it finishes, one of the waiting callers takes over with its own
+    /// loader. An entry inserted by other means while loading is kept, and
+    /// its value returned without a mark.
+    pub fn get_or_load<F, Fut>(&self, k: K, load: F) -> GetOrLoad<'_, K, V, F, Fut>
+        where K: Clone,
+              V: Clone,
+              F: FnOnce() -> Fut,
+              Fut: Future<Output = V>,
+    {
+        GetOrLoad {
+            map:    self,
+            key:    Some(k),
+            load:   Some(load),
+            flight: None,
+            state:  State::Idle,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        (0..self.shards.len()).map(|i| self.read(i).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        (0..self.shards.len()).all(|i| self.read(i).is_empty())
+    }
+
+    /// Sweep every shard in turn, the report adds up all of them.
+    pub fn gc(&self) -> super::GcReport {
+        let mut report = super::GcReport::default();
+        for i in 0..self.shards.len() {
+            report += self.write(i).gc();
+        }
+        report
+    }
+
+    /// Sweep the map every `interval` on a background thread, so collection
+    /// happens off the request path. The thread only holds a weak reference
+    /// and exits at the first tick after the map was dropped.
+    pub fn spawn_gc(self: &Arc<Self>, interval: Duration) -> JoinHandle<()>
+        where K: Send + Sync + 'static,
+              V: Send + Sync + 'static,
+    {
+        let map = Arc::downgrade(self);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            match map.upgrade() {
+                Some(map) => { map.gc(); },
+                None => break,
+            }
+        })
+    }
+}
+
+/// Future returned by `HashMap::wait_for`.
+pub struct WaitFor<'a, K: 'a, V: 'a>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    map:   &'a HashMap<K, V>,
+    key:   K,
+    // tells this future's waker apart from those of others waiting for the
+    // same key, even if they would wake the same task
+    token: u64,
+}
+
+// nothing is pinned
+impl<'a, K, V> Unpin for WaitFor<'a, K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{}
+
+impl<'a, K, V> Future for WaitFor<'a, K, V>
+    where K: std::cmp::Eq + std::hash::Hash + Clone,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let live = |map: &HashMap<K, V>, k: &K| map.read(map.shard(k)).contains_key(k);
+        if live(this.map, &this.key) {
+            return Poll::Ready(());
+        }
+        {
+            // released before the check below, inserts take the shard lock first
+            let mut waiters = this.map.waiters();
+            let wakers = waiters.entry(this.key.clone()).or_default();
+            match wakers.iter_mut().find(|(token, _)| *token == this.token) {
+                Some((_, waker)) => waker.clone_from(cx.waker()),
+                None => wakers.push((this.token, cx.waker().clone())),
+            }
+            this.map.waiting.store(waiters.len(), Ordering::SeqCst);
+        }
+        if live(this.map, &this.key) {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+impl<'a, K, V> Drop for WaitFor<'a, K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    fn drop(&mut self) {
+        if self.map.waiting.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let mut waiters = self.map.waiters();
+        let empty = match waiters.get_mut(&self.key) {
+            Some(wakers) => {
+                wakers.retain(|(token, _)| *token != self.token);
+                wakers.is_empty()
+            },
+            None => false,
+        };
+        if empty {
+            waiters.remove(&self.key);
+        }
+        self.map.waiting.store(waiters.len(), Ordering::SeqCst);
+    }
+}
+
+/// A load in progress, shared by everyone waiting for the same key.
+struct Flight<V> {
+    state: Mutex<FlightState<V>>,
+}
+
+enum FlightState<V> {
+    Loading(Vec<Waker>),
+    Done(V),
+    Abandoned,
+}
+
+impl<V> Flight<V> {
+    fn finish(&self, state: FlightState<V>) {
+        let old = std::mem::replace(&mut *self.state.lock().unwrap_or_else(|e| e.into_inner()), state);
+        if let FlightState::Loading(wakers) = old {
+            wakers.into_iter().for_each(Waker::wake);
+        }
+    }
+}
+
+enum State<Fut> {
+    Idle,
+    Leading(Pin<Box<Fut>>),
+    Waiting,
+    Done,
+}
+
+/// Future returned by `HashMap::get_or_load`.
+pub struct GetOrLoad<'a, K: 'a, V: 'a, F, Fut>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    map:    &'a HashMap<K, V>,
+    key:    Option<K>,
+    load:   Option<F>,
+    flight: Option<Arc<Flight<V>>>,
+    state:  State<Fut>,
+}
+
+// the loader future is boxed and nothing else is pinned
+impl<'a, K, V, F, Fut> Unpin for GetOrLoad<'a, K, V, F, Fut>
+    where K: std::cmp::Eq + std::hash::Hash
+{}
+
+impl<'a, K, V, F, Fut> Future for GetOrLoad<'a, K, V, F, Fut>
+    where K: std::cmp::Eq + std::hash::Hash + Clone,
+          V: Clone,
+          F: FnOnce() -> Fut,
+          Fut: Future<Output = V>,
+{
+    type Output = (V, Option<MarkOnDrop>);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            match this.state {
+                State::Idle => {
+                    let k = this.key.as_ref().expect("polled after completion");
+                    if let Some(v) = this.map.get(k) {
+                        this.state = State::Done;
+                        return Poll::Ready((v, None));
+                    }
+                    let mut flights = this.map.flights();
+                    // the leader inserts before it leaves, so check again while
+                    // no flight can leave
+                    if let Some(v) = this.map.get(k) {
+                        this.state = State::Done;
+                        return Poll::Ready((v, None));
+                    }
+                    match flights.get(k) {
+                        Some(flight) => {
+                            this.flight = Some(flight.clone());
+                            this.state  = State::Waiting;
+                        },
+                        None => {
+                            let flight = Arc::new(Flight{state: Mutex::new(FlightState::Loading(Vec::new()))});
+                            flights.insert(k.clone(), flight.clone());
+                            this.flight = Some(flight);
+                            let load = this.load.take().expect("loader already used");
+                            this.state  = State::Leading(Box::pin(load()));
+                        },
+                    }
+                },
+                State::Leading(ref mut fut) => {
+                    let v = match fut.as_mut().poll(cx) {
+                        Poll::Ready(v) => v,
+                        Poll::Pending  => return Poll::Pending,
+                    };
+                    this.state = State::Done;
+                    let k = this.key.take().unwrap();
+                    let mut flights = this.map.flights();
+                    // another thread may have inserted while we loaded, its
+                    // entry wins over the loaded value
+                    let i = this.map.shard(&k);
+                    let mut shard = this.map.write(i);
+                    let mark = shard.insert_with_policy(k.clone(), v, super::Replace::KeepExisting).ok().map(|(mark, _)| mark);
+                    let v = shard.get_live(&k).unwrap().clone();
+                    this.map.notify(i, &shard);
+                    drop(shard);
+                    flights.remove(&k);
+                    drop(flights);
+                    this.flight.take().unwrap().finish(FlightState::Done(v.clone()));
+                    return Poll::Ready((v, mark));
+                },
+                State::Waiting => {
+                    let flight = this.flight.clone().unwrap();
+                    let mut state = flight.state.lock().unwrap_or_else(|e| e.into_inner());
+                    match *state {
+                        FlightState::Loading(ref mut wakers) => {
+                            wakers.retain(|w| !w.will_wake(cx.waker()));
+                            wakers.push(cx.waker().clone());
+                            return Poll::Pending;
+                        },
+                        FlightState::Done(ref v) => {
+                            this.state = State::Done;
+                            return Poll::Ready((v.clone(), None));
+                        },
+                        FlightState::Abandoned => {
+                            this.flight = None;
+                            this.state  = State::Idle;
+                        },
+                    }
+                },
+                State::Done => panic!("polled after completion"),
+            }
+        }
+    }
+}
+
+impl<'a, K, V, F, Fut> Drop for GetOrLoad<'a, K, V, F, Fut>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    fn drop(&mut self) {
+        if let State::Leading(_) = self.state {
+            if let Some(ref k) = self.key {
+                self.map.flights().remove(k);
+            }
+            if let Some(flight) = self.flight.take() {
+                flight.finish(FlightState::Abandoned);
+            }
+        }
+    }
+}
+
+
+#[test]
+fn sync_threads() {
+    let wm : HashMap<u32, u32> = HashMap::new();
+
+    let marks : Vec<MarkOnDrop> = std::thread::scope(|s| {
+        let workers : Vec<_> = (0..4).map(|t| {
+            let wm = &wm;
+            s.spawn(move || {
+                (0..100).map(|i| wm.insert(t * 100 + i, i).0).collect::<Vec<_>>()
+            })
+        }).collect();
+        workers.into_iter().flat_map(|w| w.join().unwrap()).collect()
+    });
+
+    assert_eq!(wm.get(&101), Some(1));
+    assert_eq!(wm.len(), 400);
+
+    std::thread::spawn(move || drop(marks)).join().unwrap();
+    assert_eq!(wm.get(&101), None);
+
+    let mut count = 0;
+    wm.for_each(|_, _| count += 1);
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn sync_spawn_gc() {
+    let wm : Arc<HashMap<u32, u32>> = Arc::new(HashMap::with_shards(2));
+    let (mark, _) = wm.insert(1, 1);
+    let (_mark, _) = wm.insert(2, 2);
+    let sweeper = wm.spawn_gc(Duration::from_millis(5));
+
+    drop(mark);
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(wm.len(), 1);
+
+    drop(wm);
+    sweeper.join().unwrap();
+}
+
+#[test]
+fn sync_entry() {
+    let wm : HashMap<u32, u32> = HashMap::with_shards(2);
+    let mark = wm.entry(1, |e| {
+        let mut mark = None;
+        *e.or_insert_with(|m|{
+            mark = Some(m);
+            1
+        }) += 1;
+        mark
+    });
+    assert!(mark.is_some());
+    assert_eq!(wm.get_with(&1, |v| v * 10), Some(20));
+}
+
+// ready on the second poll
+#[cfg(test)]
+struct YieldOnce(u32, bool);
+
+#[cfg(test)]
+impl Future for YieldOnce {
+    type Output = u32;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+        if self.1 {
+            return Poll::Ready(self.0);
+        }
+        self.1 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[test]
+fn sync_get_or_load() {
+    let wm : HashMap<u32, u32> = HashMap::with_shards(2);
+    let mut cx = Context::from_waker(Waker::noop());
+
+    let mut first  = wm.get_or_load(1, || YieldOnce(10, false));
+    let mut second = wm.get_or_load(1, || -> YieldOnce { panic!("loaded twice") });
+    assert!(Pin::new(&mut first).poll(&mut cx).is_pending());
+    assert!(Pin::new(&mut second).poll(&mut cx).is_pending());
+
+    let (v, mark) = match Pin::new(&mut first).poll(&mut cx) {
+        Poll::Ready(ready) => ready,
+        Poll::Pending      => panic!("load did not finish"),
+    };
+    assert_eq!(v, 10);
+    assert!(mark.is_some());
+    match Pin::new(&mut second).poll(&mut cx) {
+        Poll::Ready((v, mark)) => assert_eq!((v, mark.is_none()), (10, true)),
+        Poll::Pending          => panic!("waiter was not released"),
+    }
+    assert_eq!(wm.get(&1), Some(10));
+
+    // a dropped leader hands the load over to a waiter
+    let mut leader = wm.get_or_load(2, || YieldOnce(10, false));
+    let mut waiter = wm.get_or_load(2, || YieldOnce(10, false));
+    assert!(Pin::new(&mut leader).poll(&mut cx).is_pending());
+    assert!(Pin::new(&mut waiter).poll(&mut cx).is_pending());
+    drop(leader);
+    assert!(Pin::new(&mut waiter).poll(&mut cx).is_pending());
+    assert!(Pin::new(&mut waiter).poll(&mut cx).is_ready());
+}
+
+#[test]
+fn sync_wait_for() {
+    struct Flag(std::sync::atomic::AtomicBool);
+    impl std::task::Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+    let flag = Arc::new(Flag(std::sync::atomic::AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let wm : HashMap<u32, u32> = HashMap::with_shards(2);
+    let mut peer = wm.wait_for(&1);
+    let mut other = wm.wait_for(&2);
+    assert!(Pin::new(&mut peer).poll(&mut cx).is_pending());
+    assert!(Pin::new(&mut other).poll(&mut cx).is_pending());
+    drop(other);
+    assert_eq!(wm.waiting.load(Ordering::SeqCst), 1);
+
+    let _mark = std::thread::scope(|s| s.spawn(|| wm.insert(1, 10).0).join().unwrap());
+    assert!(flag.0.load(Ordering::SeqCst));
+    assert!(Pin::new(&mut peer).poll(&mut cx).is_ready());
+    assert_eq!(wm.waiting.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn sync_wait_for_same_task() {
+    struct Flag(std::sync::atomic::AtomicBool);
+    impl std::task::Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+    let flag = Arc::new(Flag(std::sync::atomic::AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    // both futures share one waker, dropping one keeps the other registered
+    let wm : HashMap<u32, u32> = HashMap::with_shards(2);
+    let mut first = wm.wait_for(&1);
+    let mut second = wm.wait_for(&1);
+    assert!(Pin::new(&mut first).poll(&mut cx).is_pending());
+    assert!(Pin::new(&mut second).poll(&mut cx).is_pending());
+    assert!(Pin::new(&mut second).poll(&mut cx).is_pending());
+    drop(first);
+    assert_eq!(wm.waiting.load(Ordering::SeqCst), 1);
+
+    let _mark = wm.insert(1, 10).0;
+    assert!(flag.0.load(Ordering::SeqCst));
+    assert!(Pin::new(&mut second).poll(&mut cx).is_ready());
+}
+
+#[test]
+fn sync_get_or_load_keeps_insert() {
+    let wm : HashMap<u32, u32> = HashMap::with_shards(2);
+    let mut cx = Context::from_waker(Waker::noop());
+
+    let mut load = wm.get_or_load(1, || YieldOnce(10, false));
+    assert!(Pin::new(&mut load).poll(&mut cx).is_pending());
+    let (_mark, _) = wm.insert(1, 20);
+
+    match Pin::new(&mut load).poll(&mut cx) {
+        Poll::Ready((v, mark)) => assert_eq!((v, mark.is_none()), (20, true)),
+        Poll::Pending          => panic!("load did not finish"),
+    }
+    assert_eq!(wm.get(&1), Some(20));
+}
@@ -0,0 +1,219 @@
+//! Maps whose entries live as long as some `Arc` does, instead of a mark.
+
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::Weak;
+
+use super::GcReport;
+use super::HashMap;
+use super::MarkOnDrop;
+
+/// A value owned by a `WeakValueHashMap` entry. The entry dies once the last
+/// `Arc` of it drops, since that drops the mark along with it.
+pub struct Value<V> {
+    value: V,
+    _mark: MarkOnDrop,
+}
+
+impl<V> Deref for Value<V> {
+    type Target = V;
+    fn deref(&self) -> &V {
+        &self.value
+    }
+}
+
+/// Variant holding only weak references to its values.
+pub struct WeakValueHashMap<K, V> {
+    map: HashMap<K, Weak<Value<V>>>,
+}
+
+impl<K,V> Default for WeakValueHashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    fn default() -> Self {
+        WeakValueHashMap {
+            map: HashMap::new(),
+        }
+    }
+}
+
+impl<K, V> WeakValueHashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K,V> WeakValueHashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash
+{
+    /// Insert `v` and return the only strong reference to it, together with
+    /// the value that was live for `k` before.
+    pub fn insert(&mut self, k: K, v: V) -> (Arc<Value<V>>, Option<Arc<Value<V>>>) {
+        let mut old = None;
+        let value = Arc::new_cyclic(|weak| {
+            let (mark, replaced) = self.map.insert(k, weak.clone());
+            old = replaced.and_then(|weak| weak.upgrade());
+            Value{value: v, _mark: mark}
+        });
+        (value, old)
+    }
+
+    pub fn get<Q>(&mut self, k: &Q) -> Option<Arc<Value<V>>>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        // the last Arc may drop between the liveness check and the upgrade
+        self.map.get(k).and_then(Weak::upgrade)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        self.map.gc()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, Arc<Value<V>>)> {
+        self.map.iter().filter_map(|(k, weak)| weak.upgrade().map(|v| (k, v)))
+    }
+}
+
+/// Variant holding only weak references to its keys.
+///
+/// Keys are `Arc`s the caller already has, compared by identity like with
+/// `ByPtr`: another `Arc` of an equal key finds nothing. An entry dies once
+/// the last strong reference to its key drops. There is no mark to count
+/// that, so dead entries are swept once the map doubled since the last `gc`.
+pub struct WeakKeyHashMap<K: ?Sized, V> {
+    // keyed by the address of the key, the stored Weak keeps it from being
+    // reused until the entry is collected.
+    map:   std::collections::HashMap<usize, (Weak<K>, V)>,
+    swept: usize,
+}
+
+impl<K: ?Sized, V> Default for WeakKeyHashMap<K, V> {
+    fn default() -> Self {
+        WeakKeyHashMap {
+            map:   std::collections::HashMap::new(),
+            swept: 0,
+        }
+    }
+}
+
+impl<K: ?Sized, V> WeakKeyHashMap<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn addr<K: ?Sized>(k: &Arc<K>) -> usize {
+    Arc::as_ptr(k) as *const () as usize
+}
+
+impl<K: ?Sized, V> WeakKeyHashMap<K, V> {
+    /// Insert `v` for `k`, returning the value it had before.
+    pub fn insert(&mut self, k: &Arc<K>, v: V) -> Option<V> {
+        if self.map.len() >= 2 * self.swept {
+            self.gc();
+        }
+        // a stored entry for this address is always the one of `k`, which
+        // is live since the caller holds it
+        self.map.insert(addr(k), (Arc::downgrade(k), v)).map(|(_, v)| v)
+    }
+
+    pub fn get(&self, k: &Arc<K>) -> Option<&V> {
+        match self.map.get(&addr(k)) {
+            Some((weak, v)) if weak.strong_count() > 0 => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, k: &Arc<K>) -> Option<&mut V> {
+        match self.map.get_mut(&addr(k)) {
+            Some((weak, v)) if weak.strong_count() > 0 => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn remove(&mut self, k: &Arc<K>) -> Option<V> {
+        self.map.remove(&addr(k)).map(|(_, v)| v)
+    }
+
+    /// Number of stored entries, including ones whose key already dropped.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        let start = std::time::Instant::now();
+        let scanned = self.map.len();
+        self.map.retain(|_, (weak, _)| weak.strong_count() > 0);
+        self.swept = self.map.len();
+        GcReport {
+            scanned,
+            collected: scanned - self.map.len(),
+            elapsed:   start.elapsed(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Arc<K>, &V)> {
+        self.map.values().filter_map(|(weak, v)| weak.upgrade().map(|k| (k, v)))
+    }
+}
+
+
+#[test]
+fn weak_values() {
+    let mut wm : WeakValueHashMap<u32, String> = WeakValueHashMap::new();
+    let (a, _) = wm.insert(1, "a".to_string());
+    let (b, _) = wm.insert(2, "b".to_string());
+    let also_a = wm.get(&1).unwrap();
+    assert_eq!(also_a.as_str(), "a");
+
+    drop(a);
+    assert_eq!(wm.get(&1).map(|v| v.to_string()), Some("a".to_string()));
+    drop(also_a);
+    assert!(wm.get(&1).is_none());
+
+    let (_c, old) = wm.insert(2, "c".to_string());
+    assert!(Arc::ptr_eq(&old.unwrap(), &b));
+    drop(b);
+    assert_eq!(wm.iter().map(|(k, v)| (*k, v.to_string())).collect::<Vec<_>>(), vec![(2, "c".to_string())]);
+    wm.gc();
+    assert_eq!(wm.len(), 1);
+}
+
+#[test]
+fn weak_keys() {
+    let mut wm : WeakKeyHashMap<String, u32> = WeakKeyHashMap::new();
+    let a = Arc::new("a".to_string());
+    let b = Arc::new("a".to_string());
+    assert_eq!(wm.insert(&a, 1), None);
+    assert_eq!(wm.insert(&b, 2), None);
+    assert_eq!(wm.get(&a), Some(&1));
+    *wm.get_mut(&b).unwrap() += 1;
+    assert_eq!(wm.insert(&b.clone(), 4), Some(3));
+
+    drop(a);
+    let live : Vec<_> = wm.iter().map(|(k, v)| (k.to_string(), *v)).collect();
+    assert_eq!(live, vec![("a".to_string(), 4)]);
+    assert_eq!(wm.len(), 2);
+    wm.gc();
+    assert_eq!(wm.len(), 1);
+
+    // equal keys are still different entries
+    assert_eq!(wm.get(&Arc::new("a".to_string())), None);
+    assert_eq!(wm.remove(&b), Some(4));
+    assert!(wm.is_empty());
+}
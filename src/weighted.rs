@@ -0,0 +1,135 @@
+use super::lru::Lru;
+use super::GcReport;
+use super::MarkOnDrop;
+
+type Weigher<K, V> = Box<dyn Fn(&K, &V) -> usize + Send + Sync>;
+type Listener<K, V> = Box<dyn FnMut(K, V) + Send + Sync>;
+
+/// Variant bounded by the total weight of its entries. Each entry is weighed
+/// once on insert. When the budget is exceeded dead entries are collected
+/// first, then live entries are evicted least recently used first and handed
+/// to the eviction listener.
+///
+/// An entry heavier than the whole budget is evicted again right away.
+pub struct WeightedHashMap<K, V> {
+    lru:      Lru<K, V>,
+    budget:   usize,
+    weigher:  Weigher<K, V>,
+    on_evict: Option<Listener<K, V>>,
+}
+
+impl<K, V> WeightedHashMap<K, V>
+    where K: std::cmp::Eq + std::hash::Hash + Clone
+{
+    pub fn new<F>(budget: usize, weigher: F) -> Self
+        where F: Fn(&K, &V) -> usize + Send + Sync + 'static,
+    {
+        WeightedHashMap {
+            lru:      Lru::new(),
+            budget,
+            weigher:  Box::new(weigher),
+            on_evict: None,
+        }
+    }
+}
+
+impl<K,V> WeightedHashMap<K,V>
+    where K: std::cmp::Eq + std::hash::Hash + Clone
+{
+    /// Called with every live entry evicted to stay within the budget.
+    /// Entries removed because their mark dropped are not reported.
+    pub fn on_evict<F>(&mut self, f: F)
+        where F: FnMut(K, V) + Send + Sync + 'static,
+    {
+        self.on_evict = Some(Box::new(f));
+    }
+
+    /// Total weight of all stored entries, including dead ones not yet collected.
+    pub fn weight(&self) -> usize {
+        self.lru.weight()
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    pub fn insert(&mut self, k: K, v: V) -> (MarkOnDrop, Option<V>) {
+        let weight = (self.weigher)(&k, &v);
+        let inserted = self.lru.insert(k, v, weight);
+        let on_evict = &mut self.on_evict;
+        self.lru.shrink_to(self.budget, |k, v| {
+            if let Some(ref mut on_evict) = *on_evict {
+                on_evict(k, v);
+            }
+        });
+        inserted
+    }
+
+    pub fn get<Q>(&mut self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.lru.touch(k).map(|v| &*v)
+    }
+
+    /// Mutable access does not reweigh the entry.
+    pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.lru.touch(k)
+    }
+
+    /// Lookup without updating recency.
+    pub fn peek<Q>(&self, k: &Q) -> Option<&V>
+        where Q: ?Sized + std::cmp::Eq + std::hash::Hash,
+              K: std::borrow::Borrow<Q>,
+    {
+        self.lru.peek(k)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lru.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    pub fn gc(&mut self) -> GcReport {
+        self.lru.gc()
+    }
+
+    /// Live entries from least to most recently used.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.lru.iter()
+    }
+}
+
+
+#[test]
+fn weighted_budget() {
+    let evicted = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut wm : WeightedHashMap<u32, Vec<u8>> = WeightedHashMap::new(10, |_, v: &Vec<u8>| v.len());
+    let sink = evicted.clone();
+    wm.on_evict(move |k, v| sink.lock().unwrap().push((k, v.len())));
+
+    let (_a, _) = wm.insert(1, vec![0; 4]);
+    let (b, _)  = wm.insert(2, vec![0; 4]);
+    let (_c, _) = wm.insert(3, vec![0; 2]);
+    assert_eq!(wm.weight(), 10);
+
+    // dead entries make room first
+    drop(b);
+    let (_d, _) = wm.insert(4, vec![0; 3]);
+    assert_eq!(wm.weight(), 9);
+    assert!(evicted.lock().unwrap().is_empty());
+
+    let (_e, _) = wm.insert(5, vec![0; 5]);
+    assert_eq!(*evicted.lock().unwrap(), vec![(1, 4)]);
+    assert_eq!(wm.weight(), 10);
+    assert_eq!(wm.peek(&1), None);
+
+    // the map can move to another thread
+    std::thread::spawn(move || wm.gc()).join().unwrap();
+}